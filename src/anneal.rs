@@ -0,0 +1,185 @@
+use super::history::{self, HallOfFame, Record, Recorder};
+use super::randrange;
+use super::selection::{Adapt, Selection, Stop};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Temperature schedule, applied after every sweep.
+#[derive(Debug, Clone, Copy)]
+pub enum Cooling {
+    /// Multiply temperature by the factor.
+    Exponential(f64),
+    /// Decrease temperature by the step.
+    Linear(f64),
+    /// Keep share of accepted worse mutants near the target.
+    Adaptive(f64),
+}
+
+impl std::str::FromStr for Cooling {
+    type Err = String;
+
+    ///
+    /// Parse `exp:FACTOR`, `linear:STEP` or `adaptive:TARGET`.
+    ///
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut it = s.splitn(2, ':');
+        let name = it.next().unwrap();
+        let param = it
+            .next()
+            .ok_or_else(|| format!("missing parameter of {:?}", s))?
+            .parse::<f64>()
+            .map_err(|e| e.to_string())?;
+
+        match name {
+            "exp" => Ok(Cooling::Exponential(param)),
+            "linear" => Ok(Cooling::Linear(param)),
+            "adaptive" => Ok(Cooling::Adaptive(param)),
+            _ => Err(format!("unknown cooling {:?}", s)),
+        }
+    }
+}
+
+///
+/// Single lineage optimizer which accepts worse mutants with Metropolis
+/// probability `exp(-delta / temperature)`.
+///
+#[derive(Debug, Clone)]
+pub struct Annealer {
+    /// Initial temperature in units of fitness.
+    pub temperature: f64,
+    pub cooling: Cooling,
+    /// Mutations per sweep. Sweep is what generation is for `select`.
+    pub sweep: usize,
+    /// How many times to restart from the best state with initial
+    /// temperature once `Stop::retries` sweeps passed without improvement.
+    pub restarts: u32,
+    /// Number of mutated polygons follows share of improving mutants of a
+    /// sweep if set.
+    pub adapt: Option<Adapt>,
+    /// Record every sweep here if set.
+    pub history: Option<Arc<Recorder>>,
+    /// Save this many best distinct accepted states into `hall/run-NNN/`.
+    pub hall_of_fame: usize,
+}
+
+impl Annealer {
+    fn cool(&self, t: f64, accepted_worse: usize, worse: usize) -> f64 {
+        match self.cooling {
+            Cooling::Exponential(factor) => t * factor,
+            Cooling::Linear(step) => (t - step).max(0.),
+            Cooling::Adaptive(target) => {
+                let rate = accepted_worse as f64 / worse.max(1) as f64;
+                if rate > target {
+                    t * 0.9
+                } else {
+                    t / 0.9
+                }
+            }
+        }
+    }
+
+    pub fn run<Args, DNA>(&self, args: Args, stop: Stop) -> DNA
+    where
+        Args: Send + Sync + Clone,
+        DNA: Selection<Args>,
+    {
+        let mut current = DNA::generate(args);
+        let mut fitness = current.fitness();
+        let mut best = (current.clone(), fitness);
+        let mut t = self.temperature;
+        let mut restarts = self.restarts;
+        let mut fails = 0;
+        let mut ngen = 0;
+        let run = history::next_run();
+        let mut hall = HallOfFame::new(self.hall_of_fame);
+
+        loop {
+            let start = Instant::now();
+            ngen += 1;
+            let (mut worse, mut accepted_worse, mut better) = (0, 0, 0);
+            let last_best = best.1;
+
+            for _ in 0..self.sweep {
+                let mutant = current.mutate();
+                let mutant_fitness = mutant.fitness();
+                let delta = mutant_fitness - fitness;
+
+                let accept = if delta <= 0. {
+                    true
+                } else {
+                    worse += 1;
+                    t > 0. && randrange(0., 1.) < (-delta / t).exp()
+                };
+                if !accept {
+                    continue;
+                }
+                if delta > 0. {
+                    accepted_worse += 1;
+                } else if delta < 0. {
+                    better += 1;
+                }
+
+                current = mutant;
+                fitness = mutant_fitness;
+                hall.offer(fitness, &current);
+                if fitness < best.1 {
+                    best = (current.clone(), fitness);
+                }
+            }
+            t = self.cool(t, accepted_worse, worse);
+            if let Some(adapt) = &self.adapt {
+                let scale = adapt.update(current.scale(), better, self.sweep);
+                current.set_scale(scale);
+            }
+            if let Some(recorder) = &self.history {
+                // Single lineage: the current state is both median and
+                // worst one.
+                recorder.write(&Record {
+                    run: run.to_string(),
+                    ngen,
+                    best: best.1,
+                    median: fitness,
+                    worst: fitness,
+                    diversity: 0.,
+                    mu: 1,
+                    scale: current.scale(),
+                    millis: start.elapsed().as_millis(),
+                });
+            }
+
+            if stop.stalled(Some(last_best), best.1) {
+                // With adaptive scale the run isn't stuck until it can't
+                // get any smaller.
+                let settled =
+                    self.adapt.is_none_or(|adapt| current.scale() <= adapt.min);
+                if settled {
+                    fails += 1;
+                }
+                println!("temperature {:.3} fails {}", t, fails);
+            } else {
+                fails = 0;
+                if self.hall_of_fame > 0 {
+                    hall.export(format!("hall/run-{:03}", run)).unwrap();
+                }
+                best.0.print(ngen, best.1);
+            }
+
+            if fails == stop.retries {
+                if restarts == 0 {
+                    break;
+                }
+                restarts -= 1;
+                fails = 0;
+                t = self.temperature;
+                current = best.0.clone();
+                fitness = best.1;
+                println!("restart, {} left", restarts);
+            }
+            if stop.done(ngen) {
+                break;
+            }
+        }
+
+        best.0
+    }
+}
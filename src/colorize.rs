@@ -1,14 +1,20 @@
-use super::{draw::*, selection::*, *};
+use super::{cow::CowVec, draw::*, grid::Grid, rank::Ranking, selection::*, *};
+use rayon::iter::*;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Colors {
-    // 512 of those
-    cols: HashMap<Color, i32>,
-    n: i32,
+    /// Palette colors with number of pixels they stand for.
+    cols: Arc<Vec<(Color, u32)>>,
+    /// Colors are restricted to `cols`, a quantized or given palette.
+    fixed: bool,
+    bg: Color,
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct DNA<'a> {
     width: u32,
     height: u32,
@@ -16,8 +22,19 @@ pub struct DNA<'a> {
     colors: Colors,
     bg: Color,
     scale_muts: usize,
-    pub polygons: Vec<Polygon>,
-    divide_rate: u64,
+    pub polygons: CowVec<Polygon>,
+    /// Polygons by canvas cells.
+    grid: Grid,
+    /// Polygons by `Polygon::score`.
+    ranking: Ranking,
+    fitness: f64,
+    gouraud: bool,
+    step: f64,
+    step_decay: f64,
+    jump_ratio: f64,
+    crossover: Crossover,
+    kernel: render::Kernel,
+    snapshots: Arc<str>,
 }
 
 #[derive(Clone)]
@@ -25,100 +42,257 @@ pub struct Args<'a> {
     pub ref_img: &'a image::RgbImage,
     pub scale_muts: usize,
     pub polygons: Vec<Polygon>,
-    pub divide_rate: u64,
+    pub colors: Colors,
+    /// Mutate vertex colors instead of flat ones.
+    pub gouraud: bool,
+    /// Initial range of local color mutation.
+    pub color_step: f64,
+    /// Range of local color mutation is multiplied by this every generation.
+    pub step_decay: f64,
+    /// Probability of replacing color with random palette color instead of
+    /// shifting it a bit.
+    pub jump_ratio: f64,
+    pub crossover: Crossover,
+    pub kernel: render::Kernel,
+    /// Prefix of images of the best individual saved every generation.
+    pub snapshots: String,
 }
 
 impl Colors {
-    fn get_bg(&self) -> Color {
-        let mut max = None;
-        let mut color = None;
-
-        for (col, n) in &self.cols {
-            if max == None || max.unwrap() < n {
-                max = Some(n);
-                color = Some(col);
-            }
+    pub fn get_bg(&self) -> Color {
+        self.bg
+    }
+
+    fn generate(&self) -> Color {
+        self.cols[randrange(0, self.cols.len())].0
+    }
+
+    /// Palette colors are restricted to, if any.
+    pub fn palette(&self) -> Option<&[(Color, u32)]> {
+        if self.fixed {
+            Some(&self.cols)
+        } else {
+            None
         }
+    }
 
-        *color.unwrap()
+    /// Nearest palette color if colors are restricted to a palette.
+    pub fn snap(&self, color: Color) -> Color {
+        if self.fixed {
+            self.cols[palette::nearest(&self.cols, color)].0
+        } else {
+            color
+        }
     }
 
-    fn generate(&self) -> Color {
-        let n = randrange(0, self.cols.len());
-        *self.cols.iter().nth(n).unwrap().0
+    /// Snap flat and vertex colors of `p` to the palette.
+    pub fn restrict(&self, p: &mut Polygon) {
+        p.color = self.snap(p.color);
+        if let Some(colors) = &mut p.vertex_colors {
+            for c in colors.iter_mut() {
+                *c = self.snap(*c);
+            }
+        }
     }
 
-    fn new(img: &image::RgbImage) -> Self {
-        let mut colors = Colors {
-            cols: Default::default(),
-            n: 0,
-        };
+    fn histogram(img: &image::RgbImage) -> Vec<(Color, u32)> {
+        let mut hist = HashMap::new();
 
         for p in img.pixels() {
-            colors
-                .cols
-                .entry(Color(*p))
-                .and_modify(|e| *e += 1)
-                .or_insert(1);
-            colors.n += 1;
+            *hist.entry(Color(*p)).or_insert(0) += 1;
+        }
+
+        hist.into_iter().collect()
+    }
+
+    ///
+    /// Collect colors of the image, quantized to at most `size` colors if
+    /// it is given.
+    ///
+    pub fn new(img: &image::RgbImage, size: Option<usize>) -> Self {
+        let hist = Colors::histogram(img);
+        let cols = match size {
+            Some(size) => palette::median_cut(hist, size),
+            None => hist,
+        };
+        let bg = cols.iter().max_by_key(|(_, n)| *n).unwrap().0;
+
+        Colors {
+            cols: Arc::new(cols),
+            fixed: size.is_some(),
+            bg,
+        }
+    }
+
+    ///
+    /// Use given palette instead of colors of the image. Every pixel is
+    /// counted for the nearest palette color and background is the one
+    /// nearest to the dominant color of the image.
+    ///
+    pub fn from_palette(img: &image::RgbImage, palette: Vec<Color>) -> Self {
+        let hist = Colors::histogram(img);
+        let mut cols = c![(c, 0), for c in palette];
+
+        for (color, n) in &hist {
+            let i = palette::nearest(&cols, *color);
+            cols[i].1 += n;
         }
+        let dominant = hist.iter().max_by_key(|(_, n)| *n).unwrap().0;
+        let bg = cols[palette::nearest(&cols, dominant)].0;
 
-        colors
+        Colors {
+            cols: Arc::new(cols),
+            fixed: true,
+            bg,
+        }
+    }
+
+    /// Pin background color.
+    pub fn with_bg(self, bg: Color) -> Self {
+        Colors { bg, ..self }
     }
 }
 
-///
-/// Paint all DNA polygons onto an Image and show it.
-///
-pub fn polygons_draw(
-    width: u32,
-    height: u32,
-    bg: Color,
-    polygons: Vec<Polygon>,
-) -> image::RgbImage {
-    fn get_average_color(p: Vec<image::Rgb<u8>>) -> image::Rgb<u8> {
-        let mut r = 0;
-        let mut g = 0;
-        let mut b = 0;
+impl<'a> DNA<'a> {
+    fn calculate_fitness(&mut self) {
+        let img = render::draw(
+            self.width,
+            self.height,
+            self.bg,
+            self.polygons.iter(),
+        );
+
+        self.fitness = render::error(&img, self.ref_img, self.kernel);
+    }
 
-        for c in &p {
-            r += c[0] as u64;
-            g += c[1] as u64;
-            b += c[2] as u64;
+    ///
+    /// Color of pixel (x, y) as painted by `render::draw`.
+    ///
+    fn pixel(&self, x: i32, y: i32) -> image::Rgb<u8> {
+        let mut sum = [0u64; 3];
+        let mut n = 0;
+
+        for p in self.grid.covering(x, y, &self.polygons) {
+            let c = p.color_at(x, y);
+            for ch in 0..3 {
+                sum[ch] += c[ch] as u64;
+            }
+            n += 1;
         }
 
-        image::Rgb([
-            (r / p.len() as u64) as u8,
-            (g / p.len() as u64) as u8,
-            (b / p.len() as u64) as u8,
-        ])
+        match n {
+            0 => self.bg.0,
+            n => image::Rgb([
+                (sum[0] / n) as u8,
+                (sum[1] / n) as u8,
+                (sum[2] / n) as u8,
+            ]),
+        }
     }
 
-    let mut img = image::ImageBuffer::from_pixel(width, height, bg.0);
-    let mut pixels = vec![Vec::new(); (width * height) as usize];
+    /// Render the image in parallel using the grid.
+    fn draw(&self) -> image::RgbImage {
+        render::draw_grid(
+            self.width,
+            self.height,
+            self.bg,
+            &self.polygons,
+            &self.grid,
+        )
+    }
 
-    for p in &polygons {
-        //p.draw(&mut img);
-        let color = p.color.0;
-        for (x, y) in p.iter_points(width as i32, height as i32) {
-            pixels[(x * (width as i32) + y) as usize].push(color);
+    ///
+    /// Same DNA with other polygons, grid, ranking and, if `scored`, fitness
+    /// are built from scratch.
+    ///
+    fn with_polygons(&self, polygons: Vec<Polygon>, scored: bool) -> Self {
+        let ranking = Ranking::new(
+            c![p.score(self.ref_img, self.kernel), for p in &polygons],
+        );
+        let polygons = CowVec::from(polygons);
+        let grid =
+            Grid::new(self.width as i32, self.height as i32, polygons.iter());
+        let mut dna = Self {
+            polygons,
+            grid,
+            ranking,
+            ..self.clone()
+        };
+        if scored {
+            dna.calculate_fitness();
         }
+        dna
     }
 
-    for x in 0..width {
-        for y in 0..height {
-            let p = pixels[(x * width + y) as usize].clone();
-            let c = if p.len() == 0 {
-                bg.0
+    ///
+    /// Recolor the worst polygons. Returns the child with indexes of
+    /// recolored polygons, its fitness is not updated.
+    ///
+    fn recolor(&self) -> (Self, Vec<usize>) {
+        let mut dna = self.clone();
+        let indexes = self.ranking.worst(self.scale_muts);
+
+        dna.step = (self.step * self.step_decay).max(1.);
+        let step = dna.step.round() as i16;
+
+        for &i in &indexes {
+            let p = &mut dna.polygons[i];
+            let flat = p.color;
+            let color = if self.gouraud {
+                &mut p.vertex_colors.get_or_insert([flat; 3])[randrange(0, 3)]
             } else {
-                get_average_color(p)
+                &mut p.color
             };
 
-            img.put_pixel(x as u32, y, c);
+            *color = if randrange(0., 1.) < self.jump_ratio {
+                self.colors.generate()
+            } else {
+                self.colors.snap(color.near(step))
+            };
+            dna.ranking.set(i, p.score(self.ref_img, self.kernel));
         }
+        (dna, indexes)
     }
 
-    img
+    fn cross(&self, other: &Self, scored: bool) -> Self {
+        let polygons = self.crossover.apply(
+            &self.polygons.to_vec(),
+            &other.polygons.to_vec(),
+            self.width as i32,
+            self.height as i32,
+        );
+        self.with_polygons(polygons, scored)
+    }
+
+    ///
+    /// Update fitness after recoloring polygons with given indexes, only
+    /// pixels under them are repainted and compared by the error kernel.
+    /// `old` is the DNA before recoloring.
+    ///
+    fn calculate_delta_fitness(&mut self, old: &Self, changed: &[usize]) {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let mut pixels = Vec::new();
+
+        for i in changed {
+            pixels.extend(self.polygons[*i].iter_points(width, height));
+        }
+        pixels.sort_unstable();
+        pixels.dedup();
+
+        let mut before = Vec::with_capacity(3 * pixels.len());
+        let mut after = Vec::with_capacity(3 * pixels.len());
+        let mut reference = Vec::with_capacity(3 * pixels.len());
+        for (x, y) in pixels {
+            before.extend_from_slice(&old.pixel(x, y).0);
+            after.extend_from_slice(&self.pixel(x, y).0);
+            reference.extend_from_slice(
+                &self.ref_img.get_pixel(x as u32, y as u32).0,
+            );
+        }
+
+        self.fitness += render::error(&after, &reference, self.kernel)
+            - render::error(&before, &reference, self.kernel);
+    }
 }
 
 impl<'a> Selection<Args<'a>> for DNA<'a> {
@@ -127,98 +301,267 @@ impl<'a> Selection<Args<'a>> for DNA<'a> {
     ///
     fn generate(args: Args<'a>) -> Self {
         let (width, height) = args.ref_img.dimensions();
-        let colors = Colors::new(&args.ref_img);
+        let colors = args.colors;
         let bg = colors.get_bg();
+        let ranking = Ranking::new(
+            c![p.score(args.ref_img, args.kernel), for p in &args.polygons],
+        );
+        let polygons = CowVec::from(args.polygons);
+        let grid = Grid::new(width as i32, height as i32, polygons.iter());
 
-        DNA {
+        let mut dna = DNA {
             colors,
             bg,
             width,
             height,
             ref_img: args.ref_img,
             scale_muts: args.scale_muts,
-            polygons: args.polygons,
-            divide_rate: args.divide_rate,
-        }
+            polygons,
+            grid,
+            ranking,
+            fitness: 0.,
+            gouraud: args.gouraud,
+            step: args.color_step,
+            step_decay: args.step_decay,
+            jump_ratio: args.jump_ratio,
+            crossover: args.crossover,
+            kernel: args.kernel,
+            snapshots: args.snapshots.into(),
+        };
+        dna.calculate_fitness();
+        dna
     }
 
     fn mutate(&self) -> Self {
-        let mut dna = self.clone();
-        let mut scores: Vec<(usize, u64)> = dna
+        let (mut dna, indexes) = self.recolor();
+        dna.calculate_delta_fitness(self, &indexes);
+        dna
+    }
+
+    fn mutate_unscored(&self) -> Self {
+        self.recolor().0
+    }
+
+    fn crossover(&self, other: &Self) -> Self {
+        self.cross(other, true)
+    }
+
+    fn crossover_unscored(&self, other: &Self) -> Self {
+        self.cross(other, false)
+    }
+
+    fn set_fitness(&mut self, fitness: f64) {
+        self.fitness = fitness;
+    }
+
+    fn evaluate(&mut self) {
+        self.calculate_fitness();
+    }
+
+    fn scale(&self) -> usize {
+        self.scale_muts
+    }
+
+    fn set_scale(&mut self, scale: usize) {
+        self.scale_muts = scale.min(self.polygons.len());
+    }
+
+    ///
+    /// Share of polygons with different colors.
+    ///
+    fn distance(&self, other: &Self) -> f64 {
+        let differ = self
             .polygons
             .iter()
-            .enumerate()
-            .map(|(n, p)| (n, p.score(self.ref_img)))
-            .collect();
+            .zip(other.polygons.iter())
+            .filter(|(a, b)| {
+                a.color != b.color || a.vertex_colors != b.vertex_colors
+            })
+            .count();
+        let n = self.polygons.len().max(other.polygons.len());
+        (differ + n - self.polygons.len().min(other.polygons.len())) as f64
+            / n.max(1) as f64
+    }
 
-        scores.sort_by_key(|args: &(usize, u64)| args.1);
-        let indexes =
-            scores.iter().map(|(n, _)| *n).rev().collect::<Vec<usize>>();
-        let indexes = &indexes[..self.scale_muts];
+    fn save(&self, path: &Path) {
+        self.draw()
+            .save_with_format(path, image::ImageFormat::Png)
+            .unwrap();
+    }
 
-        for i in indexes {
-            dna.polygons[*i].color = self.colors.generate();
-        }
+    ///
+    /// Only chunks not shared with other individuals are counted.
+    ///
+    fn size_hint(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.polygons.unshared() * std::mem::size_of::<Polygon>()
+            + self.grid.size_hint()
+            + self.ranking.size_hint()
+    }
 
-        dna
+    fn full_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.polygons.len() * std::mem::size_of::<Polygon>()
+            + self.grid.size()
+            + self.ranking.size()
     }
 
-    fn fitness(&self) -> u64 {
-        let img1 = polygons_draw(
-            self.width,
-            self.height,
-            self.bg,
-            self.polygons.clone(),
-        );
-        let img2 = self.ref_img;
-        let (width, height) = img1.dimensions();
-        let mut fitness = 0;
-
-        for y in 0..height {
-            for x in 0..width {
-                let image::Rgb([r1, g1, b1]) = *img1.get_pixel(x, y);
-                let image::Rgb([r2, g2, b2]) = *img2.get_pixel(x, y);
-                let d_r = (r1 as i32 - r2 as i32) as f64;
-                let d_g = (g1 as i32 - g2 as i32) as f64;
-                let d_b = (b1 as i32 - b2 as i32) as f64;
-
-                fitness += (d_r * d_r + d_g * d_g + d_b * d_b).sqrt() as u64;
-            }
-        }
+    fn fitness(&self) -> f64 {
+        self.fitness
+    }
 
-        fitness / self.divide_rate
+    fn polygons(&self) -> Option<&CowVec<Polygon>> {
+        Some(&self.polygons)
     }
 
-    fn print(&self, ngen: u64, fitness: u64) {
-        let print = (fitness * self.divide_rate) as f64
-            / self.width as f64
-            / self.height as f64;
-        let print = print * print;
-        let print = (print / 3.).sqrt();
+    fn print(&self, ngen: u64, fitness: f64) {
+        let print = fitness / self.width as f64 / self.height as f64;
+        let print = (print * print / 3.).sqrt();
         println!(
             "colorize_first: Showing generation {} fitness: {} {}",
             ngen, print, fitness
         );
 
-        let img = polygons_draw(
-            self.width,
-            self.height,
-            self.bg,
-            self.polygons.clone(),
-        );
+        let img = self.draw();
 
         img.save_with_format(
-            format!("./img/{:0>4}.png", ngen),
+            format!("{}{:0>4}.png", self.snapshots, ngen),
             image::ImageFormat::Png,
         )
         .unwrap();
     }
 }
 
-pub fn generate_colors(pol: &mut Vec<Polygon>, ref_img: &image::RgbImage) {
-    let colors = Colors::new(&ref_img);
-
-    for mut p in pol {
+pub fn generate_colors<'a>(
+    pol: impl IntoIterator<Item = &'a mut Polygon>,
+    colors: &Colors,
+    gouraud: bool,
+) {
+    for p in pol {
         p.color = colors.generate();
+        if gouraud {
+            p.vertex_colors =
+                Some([colors.generate(), colors.generate(), colors.generate()]);
+        }
+    }
+}
+
+///
+/// Set colors of every polygon to the closed-form best fit, snapped to the
+/// palette if there is one.
+///
+pub fn fit_colors(
+    pol: &mut Vec<Polygon>,
+    ref_img: &image::RgbImage,
+    colors: &Colors,
+    gouraud: bool,
+) {
+    pol.par_iter_mut().for_each(|p| {
+        if gouraud && p.vertex_colors.is_none() {
+            p.vertex_colors = Some([p.color; 3]);
+        }
+        p.fit(ref_img);
+        colors.restrict(p);
+    });
+}
+
+impl<'a> nsga::MultiObjective<Args<'a>> for DNA<'a> {
+    fn objectives(&self) -> Vec<f64> {
+        vec![self.fitness(), self.polygons.len() as f64]
+    }
+
+    fn size(&self) -> usize {
+        self.polygons.len()
+    }
+
+    ///
+    /// Remove random polygons, keeping at least one, or add copies of random
+    /// ones moved to random places with random palette colors.
+    ///
+    fn mutate_structure(&self, delta: i64) -> Self {
+        if delta == 0 {
+            return self.clone();
+        }
+        let mut polygons = self.polygons.to_vec();
+        let n = polygons.len();
+        if n == 0 {
+            return self.clone();
+        }
+
+        if delta < 0 {
+            for _i in 0..(-delta as usize).min(n - 1) {
+                polygons.swap_remove(randrange(0, polygons.len()));
+            }
+        } else {
+            for _i in 0..delta {
+                let mut p = polygons[randrange(0, n)];
+                let (cx, cy) = p.center();
+                let shift = Point {
+                    x: randrange(0, self.width as i32) - cx,
+                    y: randrange(0, self.height as i32) - cy,
+                };
+
+                for point in &mut p.points {
+                    *point = *point + shift;
+                }
+                p.color = self.colors.generate();
+                p.vertex_colors = p.vertex_colors.map(|_| [p.color; 3]);
+                polygons.push(p);
+            }
+        }
+        self.with_polygons(polygons, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nsga::MultiObjective;
+
+    fn image() -> image::RgbImage {
+        image::RgbImage::from_fn(37, 29, |x, y| {
+            image::Rgb([(x * 7) as u8, (y * 9) as u8, ((x + y) * 3) as u8])
+        })
+    }
+
+    fn dna(img: &image::RgbImage, n: usize) -> DNA<'_> {
+        DNA::generate(Args {
+            ref_img: img,
+            scale_muts: 5,
+            polygons: c![Polygon::generate(37, 29, (10, 10), 4), for _i in 0..n],
+            colors: Colors::new(img, Some(16)),
+            gouraud: false,
+            color_step: 20.,
+            step_decay: 1.,
+            jump_ratio: 0.5,
+            crossover: Crossover::Uniform,
+            kernel: render::Kernel::Simd,
+            snapshots: "./img/".to_string(),
+        })
+    }
+
+    #[test]
+    fn delta_fitness_matches_full() {
+        let img = image();
+        let mut dna = dna(&img, 40);
+
+        for _i in 0..20 {
+            dna = dna.mutate();
+            let mut full = dna.clone();
+            full.calculate_fitness();
+            assert!((dna.fitness - full.fitness).abs() < 1e-6 * full.fitness);
+        }
+    }
+
+    #[test]
+    fn structure_keeps_a_polygon() {
+        let img = image();
+        let empty = dna(&img, 0);
+        assert_eq!(empty.mutate_structure(-2).polygons.len(), 0);
+        assert_eq!(empty.mutate_structure(2).polygons.len(), 0);
+
+        let one = dna(&img, 1);
+        assert_eq!(one.mutate_structure(-1).polygons.len(), 1);
+        assert_eq!(one.mutate_structure(2).polygons.len(), 3);
     }
 }
@@ -0,0 +1,173 @@
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+/// Number of elements in a chunk by default.
+const CHUNK: usize = 4096;
+
+///
+/// Vector split into chunks shared between its copies. A chunk is copied
+/// only when it is changed, so a mutant costs as much as chunks it touches.
+///
+#[derive(Debug, Clone)]
+pub struct CowVec<T> {
+    chunks: Vec<Arc<Vec<T>>>,
+    len: usize,
+    chunk: usize,
+}
+
+impl<T: Clone> CowVec<T> {
+    ///
+    /// Vector with `chunk` elements in a chunk, for big elements changed
+    /// one at a time.
+    ///
+    pub fn chunked(v: Vec<T>, chunk: usize) -> Self {
+        let chunk = chunk.max(1);
+
+        CowVec {
+            chunks: v.chunks(chunk).map(|c| Arc::new(c.to_vec())).collect(),
+            len: v.len(),
+            chunk,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().flat_map(|c| c.iter())
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    pub fn push(&mut self, v: T) {
+        if self.len.is_multiple_of(self.chunk) {
+            self.chunks.push(Arc::new(Vec::with_capacity(self.chunk)));
+        }
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(v);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let last = Arc::make_mut(self.chunks.last_mut()?);
+        let v = last.pop();
+
+        if last.is_empty() {
+            self.chunks.pop();
+        }
+        self.len -= 1;
+        v
+    }
+
+    ///
+    /// Indexes of elements of `self` which differ from ones of `other` or
+    /// are missing there. Shared chunks are skipped without comparing.
+    ///
+    pub fn diff(&self, other: &Self) -> Vec<usize>
+    where
+        T: PartialEq,
+    {
+        let mut diff = Vec::new();
+
+        for (n, chunk) in self.chunks.iter().enumerate() {
+            let theirs = other.chunks.get(n);
+            if theirs.is_some_and(|c| Arc::ptr_eq(c, chunk)) {
+                continue;
+            }
+
+            for (i, v) in chunk.iter().enumerate() {
+                if theirs.and_then(|c| c.get(i)) != Some(v) {
+                    diff.push(n * self.chunk + i);
+                }
+            }
+        }
+        diff
+    }
+
+    ///
+    /// Number of elements in chunks owned only by this copy, for memory
+    /// accounting.
+    ///
+    pub fn unshared(&self) -> usize {
+        self.unshared_size(|_| 1)
+    }
+
+    /// Sum of `size` of elements in chunks owned only by this copy.
+    pub fn unshared_size(&self, size: impl Fn(&T) -> usize) -> usize {
+        self.chunks
+            .iter()
+            .filter(|c| Arc::strong_count(c) == 1)
+            .flat_map(|c| c.iter())
+            .map(size)
+            .sum()
+    }
+}
+
+impl<T: Clone> From<Vec<T>> for CowVec<T> {
+    fn from(v: Vec<T>) -> Self {
+        CowVec::chunked(v, CHUNK)
+    }
+}
+
+impl<T> Index<usize> for CowVec<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        &self.chunks[i / self.chunk][i % self.chunk]
+    }
+}
+
+impl<T: Clone> IndexMut<usize> for CowVec<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        &mut Arc::make_mut(&mut self.chunks[i / self.chunk])[i % self.chunk]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clone_shares_until_changed() {
+        let a = CowVec::chunked((0..10).collect::<Vec<_>>(), 4);
+        assert_eq!(a.unshared(), 10);
+
+        let mut b = a.clone();
+        assert_eq!(a.unshared(), 0);
+        assert_eq!(b.unshared(), 0);
+
+        b[5] = 50;
+        assert_eq!(a[5], 5);
+        assert_eq!(b[5], 50);
+        assert_eq!(b.unshared(), 4);
+        assert_eq!(b.diff(&a), vec![5]);
+        assert_eq!(a.diff(&b), vec![5]);
+    }
+
+    #[test]
+    fn push_and_pop_across_chunks() {
+        let a = CowVec::chunked(vec![0, 1, 2, 3], 2);
+        let mut b = a.clone();
+        b.push(4);
+        assert_eq!(b.len(), 5);
+        assert_eq!(b.unshared(), 1);
+        assert_eq!(b.diff(&a), vec![4]);
+
+        assert_eq!(b.pop(), Some(4));
+        assert_eq!(b.pop(), Some(3));
+        assert_eq!(b.to_vec(), vec![0, 1, 2]);
+        assert_eq!(a.to_vec(), vec![0, 1, 2, 3]);
+        assert_eq!(b.unshared(), 1);
+    }
+
+    #[test]
+    fn unshared_size_counts_owned_chunks() {
+        let a = CowVec::chunked(vec![vec![0u8; 3], vec![0; 5], vec![0; 7]], 2);
+        let mut b = a.clone();
+        b[2].push(1);
+        assert_eq!(b.unshared_size(|v| v.len()), 8);
+        assert_eq!(a.unshared_size(|v| v.len()), 7);
+    }
+}
@@ -1,32 +1,64 @@
 use super::randrange;
+use super::render::{self, Kernel};
 use derive_more::{Add, Sub};
-use image;
 use std::cmp::*;
 
 #[derive(Copy, Clone, Hash, PartialEq, Eq, Debug)]
 pub struct Color(pub image::Rgb<u8>);
 
-#[derive(Copy, Clone, Add, Sub, Debug)]
+#[derive(Copy, Clone, Add, Sub, PartialEq, Debug)]
 pub struct Point {
     pub x: i32,
     pub y: i32,
 }
 
+/// Way of combining polygons of two parents.
 #[derive(Debug, Copy, Clone)]
+pub enum Crossover {
+    /// Every polygon is taken from a random parent.
+    Uniform,
+    /// Polygons on one side of a random cut line are taken from one parent
+    /// and the rest from the other.
+    Spatial,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Polygon {
     pub color: Color,
     pub points: [Point; 3],
+    /// Colors of `points` interpolated over the triangle (Gouraud shading).
+    /// Flat `color` is used if not set.
+    pub vertex_colors: Option<[Color; 3]>,
 }
 
-// impl Color {
-//     pub fn near(&mut self, range: i16) {
-//         let Color(image::Rgb(arr)) = self;
-//
-//         arr[0] = (arr[0] as i16 + randrange(-range, range)) as u8;
-//         arr[1] = (arr[1] as i16 + randrange(-range, range)) as u8;
-//         arr[2] = (arr[2] as i16 + randrange(-range, range)) as u8;
-//     }
-// }
+impl Color {
+    pub fn hex(&self) -> String {
+        let Color(image::Rgb([r, g, b])) = self;
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    /// Parse `#rrggbb` or `rrggbb`.
+    pub fn from_hex(s: &str) -> Option<Self> {
+        let s = s.trim_start_matches('#');
+        if s.len() != 6 {
+            return None;
+        }
+
+        let channel = |i: usize| u8::from_str_radix(s.get(i..i + 2)?, 16).ok();
+        Some(Color(image::Rgb([channel(0)?, channel(2)?, channel(4)?])))
+    }
+
+    /// Random color within `range` from this one in every channel.
+    pub fn near(&self, range: i16) -> Self {
+        let Color(image::Rgb(arr)) = *self;
+        let range = range.max(1);
+        let shift = |c: u8| {
+            (c as i16 + randrange(-range, range + 1)).clamp(0, 255) as u8
+        };
+
+        Color(image::Rgb([shift(arr[0]), shift(arr[1]), shift(arr[2])]))
+    }
+}
 
 impl Point {
     const OFFSET: i32 = 3;
@@ -47,6 +79,87 @@ impl Point {
     }
 }
 
+impl std::str::FromStr for Crossover {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Crossover::Uniform),
+            "spatial" => Ok(Crossover::Spatial),
+            _ => Err(format!("unknown crossover {:?}", s)),
+        }
+    }
+}
+
+impl Crossover {
+    ///
+    /// Combine two polygon lists of the same length into a new one.
+    ///
+    pub fn apply(
+        self,
+        a: &[Polygon],
+        b: &[Polygon],
+        width: i32,
+        height: i32,
+    ) -> Vec<Polygon> {
+        match self {
+            Crossover::Uniform => a
+                .iter()
+                .zip(b)
+                .map(|(pa, pb)| if randrange(0, 2) == 0 { *pa } else { *pb })
+                .collect(),
+            Crossover::Spatial => {
+                let vertical = randrange(0, 2) == 0;
+                let cut = randrange(0, if vertical { width } else { height });
+                let left = |p: &Polygon| {
+                    let (x, y) = p.center();
+                    (if vertical { x } else { y }) < cut
+                };
+
+                let mut polygons = c![*p, for p in a, if left(p)];
+                polygons.extend(b.iter().filter(|p| !left(p)));
+                polygons.truncate(a.len());
+
+                let missing = a.len() - polygons.len();
+                polygons.extend(a.iter().filter(|p| !left(p)).take(missing));
+                polygons
+            }
+        }
+    }
+}
+
+///
+/// Range of x of row `i` of triangle with points sorted by y, counting from
+/// the top one.
+///
+fn span(p0: Point, p1: Point, p2: Point, i: i32) -> (i32, i32) {
+    let total_height = p2.y - p0.y;
+    let second_half = i > p1.y - p0.y || p1.y == p0.y;
+    let segment_height = if second_half {
+        p2.y - p1.y
+    } else {
+        p1.y - p0.y
+    };
+    let alpha: f64 = (i as f64) / (total_height as f64);
+    let tmp = if second_half { p1.y - p0.y } else { 0 };
+    // be careful: with above conditions no division by zero here
+    let beta: f64 = (i - tmp) as f64 / segment_height as f64;
+
+    let a_mat = ((p0.x as f64) + ((p2.x - p0.x) as f64) * alpha) as i32;
+
+    let b_mat = if second_half {
+        (p1.x as f64 + (p2.x - p1.x) as f64 * beta) as i32
+    } else {
+        (p0.x as f64 + (p1.x - p0.x) as f64 * beta) as i32
+    };
+
+    if a_mat > b_mat {
+        (b_mat, a_mat)
+    } else {
+        (a_mat, b_mat)
+    }
+}
+
 pub struct PolygonIterator {
     width: i32,
     height: i32,
@@ -70,42 +183,8 @@ impl Iterator for PolygonIterator {
                 return None;
             }
 
-            if self.inter == None {
-                let second_half =
-                    self.i > self.p1.y - self.p0.y || self.p1.y == self.p0.y;
-                let segment_height = if second_half {
-                    self.p2.y - self.p1.y
-                } else {
-                    self.p1.y - self.p0.y
-                };
-                let alpha: f64 = (self.i as f64) / (total_height as f64);
-                let tmp = if second_half {
-                    self.p1.y - self.p0.y
-                } else {
-                    0
-                };
-                // be careful: with above conditions no division by zero here
-                let beta: f64 = (self.i - tmp) as f64 / segment_height as f64;
-
-                let a_mat = ((self.p0.x as f64)
-                    + ((self.p2.x - self.p0.x) as f64) * alpha)
-                    as i32;
-
-                let b_mat = if second_half {
-                    (self.p1.x as f64 + (self.p2.x - self.p1.x) as f64 * beta)
-                        as i32
-                } else {
-                    (self.p0.x as f64 + (self.p1.x - self.p0.x) as f64 * beta)
-                        as i32
-                };
-
-                let (a_mat, b_mat) = if a_mat > b_mat {
-                    (b_mat, a_mat)
-                } else {
-                    (a_mat, b_mat)
-                };
-
-                self.inter = Some((a_mat, b_mat));
+            if self.inter.is_none() {
+                self.inter = Some(span(self.p0, self.p1, self.p2, self.i));
             }
 
             let (a_mat, b_mat) = self.inter.unwrap();
@@ -133,6 +212,17 @@ impl Iterator for PolygonIterator {
 impl Polygon {
     const COLOR_BLACK: Color = Color(image::Rgb([255, 255, 255]));
 
+    /// Flat colored triangle.
+    pub fn new(mut points: [Point; 3], color: Color) -> Self {
+        points.sort_by_key(|p| p.y);
+
+        Polygon {
+            color,
+            points,
+            vertex_colors: None,
+        }
+    }
+
     ///
     /// Drawing algorithm from here: https://habr.com/en/post/248159/
     ///
@@ -152,22 +242,182 @@ impl Polygon {
         }
     }
 
-    pub fn score(&self, ref_img: &image::RgbImage) -> u64 {
+    ///
+    /// Whether (x, y) is one of the points of `iter_points`.
+    ///
+    pub fn contains(&self, x: i32, y: i32, width: i32, height: i32) -> bool {
+        if x < 0 || x >= width || y < 0 || y >= height {
+            return false;
+        }
+
+        let [p0, p1, p2] = self.points;
+        let i = y - p0.y;
+        if i < 0 || i >= p2.y - p0.y {
+            return false;
+        }
+
+        let (a, b) = span(p0, p1, p2, i);
+        x >= a && x < b
+    }
+
+    ///
+    /// Rows of `iter_points` as (y, first x, last x + 1).
+    ///
+    pub fn rows(
+        &self,
+        width: i32,
+        height: i32,
+    ) -> impl Iterator<Item = (i32, i32, i32)> {
+        let [p0, p1, p2] = self.points;
+
+        (0..p2.y - p0.y).filter_map(move |i| {
+            let y = p0.y + i;
+            let (a, b) = span(p0, p1, p2, i);
+            let (a, b) = (a.max(0), b.min(width));
+
+            if y < 0 || y >= height || a >= b {
+                None
+            } else {
+                Some((y, a, b))
+            }
+        })
+    }
+
+    /// Bounding box as (min x, min y, max x, max y), inclusive.
+    pub fn bbox(&self) -> (i32, i32, i32, i32) {
+        let xs = c![p.x, for p in &self.points];
+        let ys = c![p.y, for p in &self.points];
+
+        (
+            *xs.iter().min().unwrap(),
+            *ys.iter().min().unwrap(),
+            *xs.iter().max().unwrap(),
+            *ys.iter().max().unwrap(),
+        )
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        let [p0, p1, p2] = self.points;
+        ((p0.x + p1.x + p2.x) / 3, (p0.y + p1.y + p2.y) / 3)
+    }
+
+    ///
+    /// Barycentric weights of (x, y) relative to `points`.
+    ///
+    fn weights(&self, x: i32, y: i32) -> Option<[f64; 3]> {
+        let [p0, p1, p2] = self.points;
+        let d = ((p1.y - p2.y) * (p0.x - p2.x) + (p2.x - p1.x) * (p0.y - p2.y))
+            as f64;
+        if d == 0. {
+            return None;
+        }
+
+        let l0 = ((p1.y - p2.y) * (x - p2.x) + (p2.x - p1.x) * (y - p2.y))
+            as f64
+            / d;
+        let l1 = ((p2.y - p0.y) * (x - p2.x) + (p0.x - p2.x) * (y - p2.y))
+            as f64
+            / d;
+        let l = [l0.max(0.), l1.max(0.), (1. - l0 - l1).max(0.)];
+        let sum = l[0] + l[1] + l[2];
+
+        Some([l[0] / sum, l[1] / sum, l[2] / sum])
+    }
+
+    pub fn color_at(&self, x: i32, y: i32) -> image::Rgb<u8> {
+        let colors = match self.vertex_colors {
+            Some(colors) => colors,
+            None => return self.color.0,
+        };
+        let w = match self.weights(x, y) {
+            Some(w) => w,
+            None => return self.color.0,
+        };
+
+        let mut rgb = [0u8; 3];
+        for (ch, v) in rgb.iter_mut().enumerate() {
+            let c = c![(colors[i].0)[ch] as f64 * w[i], for i in 0..3];
+            *v = (c.iter().sum::<f64>().round()) as u8;
+        }
+        image::Rgb(rgb)
+    }
+
+    ///
+    /// Set colors to the least squares fit of covered pixels of `ref_img`.
+    ///
+    pub fn fit(&mut self, ref_img: &image::RgbImage) {
         let (width, height) = ref_img.dimensions();
-        let Color(image::Rgb([r, g, b])) = self.color;
-        let mut score = 0;
+        let mut ata = [[0f64; 3]; 3];
+        let mut atb = [[0f64; 3]; 3];
+        let mut sum = [0f64; 3];
+        let mut n = 0;
+
         for (x, y) in self.iter_points(width as i32, height as i32) {
-            let image::Rgb([r1, g1, b1]) =
-                *ref_img.get_pixel(x as u32, y as u32);
-            let d_r = (r as i32 - r1 as i32) as f64;
-            let d_g = (g as i32 - g1 as i32) as f64;
-            let d_b = (b as i32 - b1 as i32) as f64;
+            let px = ref_img.get_pixel(x as u32, y as u32);
+            let w = self.weights(x, y).unwrap_or([1. / 3.; 3]);
+
+            for i in 0..3 {
+                sum[i] += px[i] as f64;
+                for j in 0..3 {
+                    ata[i][j] += w[i] * w[j];
+                    atb[i][j] += w[i] * px[j] as f64;
+                }
+            }
+            n += 1;
+        }
+        if n == 0 {
+            return;
+        }
 
-            score += (d_r * d_r + d_g * d_g + d_b * d_b).sqrt() as u64;
+        let to_color = |c: [f64; 3]| {
+            let to_u8 = |v: f64| v.round().clamp(0., 255.) as u8;
+            Color(image::Rgb([to_u8(c[0]), to_u8(c[1]), to_u8(c[2])]))
+        };
+        let n = n as f64;
+        self.color = to_color([sum[0] / n, sum[1] / n, sum[2] / n]);
+
+        if self.vertex_colors.is_some() {
+            self.vertex_colors = Some(match solve3(ata, atb) {
+                Some(x) => [to_color(x[0]), to_color(x[1]), to_color(x[2])],
+                None => [self.color; 3],
+            });
+        }
+    }
+
+    pub fn score(&self, ref_img: &image::RgbImage, kernel: Kernel) -> u64 {
+        let (width, height) = ref_img.dimensions();
+        let raw: &[u8] = ref_img;
+        let mut colors = Vec::new();
+        let mut score = 0;
+
+        for (y, x0, x1) in self.rows(width as i32, height as i32) {
+            let start = (y as usize * width as usize + x0 as usize) * 3;
+            let row = &raw[start..start + (x1 - x0) as usize * 3];
+
+            colors.clear();
+            for x in x0..x1 {
+                colors.extend_from_slice(&self.color_at(x, y).0);
+            }
+            score += render::score(&colors, row, kernel);
         }
         score
     }
 
+    ///
+    /// Rescale polygon coordinates by `num / den`, e.g. when moving to the
+    /// next level of the resolution pyramid.
+    ///
+    pub fn scale(&self, num: i32, den: i32) -> Self {
+        let mut points = self.points;
+        for p in &mut points {
+            p.x = p.x * num / den;
+            p.y = p.y * num / den;
+        }
+        points.sort_by_key(|p| p.y);
+
+        Polygon { points, ..*self }
+    }
+
     pub fn generate(
         img_w: i32,
         img_h: i32,
@@ -212,6 +462,35 @@ impl Polygon {
         Polygon {
             color: Polygon::COLOR_BLACK,
             points,
+            vertex_colors: None,
+        }
+    }
+}
+
+///
+/// Solve `a * x = b` for three right-hand sides with Cramer's rule.
+///
+fn solve3(a: [[f64; 3]; 3], b: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    fn det(m: [[f64; 3]; 3]) -> f64 {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    let d = det(a);
+    if d.abs() < 1e-9 {
+        return None;
+    }
+
+    let mut x = [[0f64; 3]; 3];
+    for ch in 0..3 {
+        for i in 0..3 {
+            let mut m = a;
+            for (row, b) in m.iter_mut().zip(&b) {
+                row[i] = b[ch];
+            }
+            x[i][ch] = det(m) / d;
         }
     }
+    Some(x)
 }
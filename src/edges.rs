@@ -0,0 +1,154 @@
+use super::draw::*;
+use image::imageops;
+use imageproc::{edges, gradients};
+
+/// How edges of the reference image are found.
+#[derive(Debug, Clone, Copy)]
+pub enum Detector {
+    /// Gradient magnitude, strength of edges is kept.
+    Sobel,
+    /// Thin edges, every one is of full strength.
+    Canny,
+}
+
+impl std::str::FromStr for Detector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sobel" => Ok(Detector::Sobel),
+            "canny" => Ok(Detector::Canny),
+            _ => Err(format!("unknown edge detector {:?}", s)),
+        }
+    }
+}
+
+///
+/// Strength of image edges in every pixel, from 0 to 1.
+///
+#[derive(Debug, Clone)]
+pub struct EdgeMap {
+    width: i32,
+    height: i32,
+    strength: Vec<f32>,
+}
+
+impl EdgeMap {
+    ///
+    /// Edges of `img`, `thresholds` are low and high hysteresis thresholds
+    /// of Canny.
+    ///
+    pub fn new(
+        img: &image::RgbImage,
+        detector: Detector,
+        thresholds: (f32, f32),
+    ) -> Self {
+        let gray = imageops::grayscale(img);
+        let strength = match detector {
+            Detector::Sobel => {
+                let grad = gradients::sobel_gradients(&gray);
+                let max = grad.pixels().map(|p| p[0]).max().unwrap_or(0);
+                c![p[0] as f32 / max.max(1) as f32, for p in grad.pixels()]
+            }
+            Detector::Canny => {
+                let edges = edges::canny(&gray, thresholds.0, thresholds.1);
+                c![p[0] as f32 / 255., for p in edges.pixels()]
+            }
+        };
+
+        EdgeMap {
+            width: img.width() as i32,
+            height: img.height() as i32,
+            strength,
+        }
+    }
+
+    fn at(&self, x: i32, y: i32) -> f64 {
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            return 0.;
+        }
+        self.strength[(y * self.width + x) as usize] as f64
+    }
+
+    /// Strongest edge within a pixel of (x, y).
+    fn near(&self, x: i32, y: i32) -> f64 {
+        c![self.at(x + dx, y + dy), for dx in -1..=1, for dy in -1..=1]
+            .into_iter()
+            .fold(0., f64::max)
+    }
+
+    ///
+    /// Sum of strength along segment from `a` to `b`, one sample per pixel.
+    /// Edges within a pixel of the segment count, as thin edges of a step
+    /// lie on either side of it.
+    ///
+    fn along(&self, a: Point, b: Point) -> f64 {
+        let steps = (b.x - a.x).abs().max((b.y - a.y).abs()).max(1);
+
+        (0..steps)
+            .map(|i| {
+                let t = i as f64 / steps as f64;
+                let x = a.x as f64 + (b.x - a.x) as f64 * t;
+                let y = a.y as f64 + (b.y - a.y) as f64 * t;
+                self.near(x.round() as i32, y.round() as i32)
+            })
+            .sum()
+    }
+
+    ///
+    /// Edges covered by the polygon minus edges along its sides: negative
+    /// for polygons following image edges, positive for ones straddling
+    /// them. Pixels within a pixel of a side count only as the side, so
+    /// that an edge under a side isn't rewarded and penalized at once.
+    ///
+    pub fn term(&self, p: &Polygon) -> f64 {
+        let [p0, p1, p2] = p.points;
+        let sides = [(p0, p1), (p1, p2), (p2, p0)];
+        let near = |x: i32, y: i32| {
+            sides.iter().any(|(a, b)| {
+                let (dx, dy) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+                let cross = dx * (y - a.y) as f64 - dy * (x - a.x) as f64;
+                cross.abs() <= dx.hypot(dy)
+            })
+        };
+
+        let inside: f64 = p
+            .iter_points(self.width, self.height)
+            .filter(|(x, y)| !near(*x, *y))
+            .map(|(x, y)| self.at(x, y))
+            .sum();
+        let sides =
+            self.along(p0, p1) + self.along(p1, p2) + self.along(p2, p0);
+
+        inside - sides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{step, triangle, BLACK};
+
+    #[test]
+    fn sides_along_edge_are_rewarded() {
+        for detector in &[Detector::Sobel, Detector::Canny] {
+            let edges = EdgeMap::new(&step(40, 40), *detector, (50., 100.));
+            let along = triangle([(20, 2), (20, 38), (36, 20)], BLACK);
+            let across = triangle([(4, 20), (36, 4), (36, 36)], BLACK);
+            let away = triangle([(26, 4), (38, 4), (38, 36)], BLACK);
+
+            assert!(edges.term(&along) < 0., "{:?}", detector);
+            assert!(edges.term(&across) > 0., "{:?}", detector);
+            assert_eq!(edges.term(&away), 0.);
+        }
+    }
+
+    #[test]
+    fn canny_thresholds_are_used() {
+        let edges = EdgeMap::new(&step(40, 40), Detector::Canny, (50., 100.));
+        assert!(edges.strength.iter().any(|s| *s > 0.));
+
+        let none = EdgeMap::new(&step(40, 40), Detector::Canny, (1e6, 2e6));
+        assert!(none.strength.iter().all(|s| *s == 0.));
+    }
+}
@@ -0,0 +1,39 @@
+//! Images and polygons shared by tests.
+use super::draw::*;
+use super::randrange;
+
+pub(crate) const BLACK: Color = Color(image::Rgb([0, 0, 0]));
+
+/// Black left half and white right half, the edge is at x = `width` / 2.
+pub(crate) fn step(width: u32, height: u32) -> image::RgbImage {
+    image::RgbImage::from_fn(width, height, |x, _y| {
+        image::Rgb(if x < width / 2 { [0; 3] } else { [255; 3] })
+    })
+}
+
+pub(crate) fn triangle(points: [(i32, i32); 3], color: Color) -> Polygon {
+    let [a, b, c] = points;
+    Polygon::new(
+        [
+            Point { x: a.0, y: a.1 },
+            Point { x: b.0, y: b.1 },
+            Point { x: c.0, y: c.1 },
+        ],
+        color,
+    )
+}
+
+///
+/// Gray triangle with vertices anywhere in the image or up to `margin`
+/// pixels out of it.
+///
+pub(crate) fn random_triangle(width: i32, height: i32, margin: i32) -> Polygon {
+    let point = || {
+        (
+            randrange(-margin, width + margin),
+            randrange(-margin, height + margin),
+        )
+    };
+    let color = Color(image::Rgb([randrange(0, 255); 3]));
+    triangle([point(), point(), point()], color)
+}
@@ -0,0 +1,147 @@
+use super::{cow::CowVec, draw::*};
+
+/// Side of a grid cell in pixels.
+const TILE: i32 = 16;
+
+///
+/// Uniform grid over the canvas mapping every cell to indexes of polygons
+/// whose bounding boxes overlap it. Every row of cells is a separate chunk
+/// shared between copies.
+///
+#[derive(Debug, Clone)]
+pub struct Grid {
+    width: i32,
+    height: i32,
+    cols: i32,
+    rows: i32,
+    cells: CowVec<Vec<usize>>,
+}
+
+impl Grid {
+    pub fn new<'a, I>(width: i32, height: i32, polygons: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Polygon>,
+    {
+        let cols = (width + TILE - 1) / TILE;
+        let rows = (height + TILE - 1) / TILE;
+        let mut grid = Grid {
+            width,
+            height,
+            cols,
+            rows,
+            cells: CowVec::chunked(
+                vec![Vec::new(); (cols * rows).max(0) as usize],
+                cols.max(1) as usize,
+            ),
+        };
+
+        for (i, p) in polygons.into_iter().enumerate() {
+            grid.insert(i, p);
+        }
+        grid
+    }
+
+    /// Indexes of cells overlapped by bounding box of `p`.
+    fn cells_of(&self, p: &Polygon) -> Vec<usize> {
+        let (x0, y0, x1, y1) = p.bbox();
+        let clamp = |v: i32, n: i32| (v / TILE).max(0).min(n - 1);
+
+        if x1 < 0 || y1 < 0 || x0 >= self.width || y0 >= self.height {
+            return Vec::new();
+        }
+
+        let (c0, c1) = (clamp(x0, self.cols), clamp(x1, self.cols));
+        let (r0, r1) = (clamp(y0, self.rows), clamp(y1, self.rows));
+        c![
+            (r * self.cols + c) as usize,
+            for r in r0..=r1,
+            for c in c0..=c1
+        ]
+    }
+
+    pub fn insert(&mut self, i: usize, p: &Polygon) {
+        for cell in self.cells_of(p) {
+            self.cells[cell].push(i);
+        }
+    }
+
+    pub fn remove(&mut self, i: usize, p: &Polygon) {
+        for cell in self.cells_of(p) {
+            self.cells[cell].retain(|j| *j != i);
+        }
+    }
+
+    /// Polygons which may cover some of rows from `top` to `bottom`
+    /// (exclusive), each once.
+    pub fn rows(&self, top: i32, bottom: i32) -> Vec<usize> {
+        self.rect(0, top, self.width, bottom)
+    }
+
+    /// Polygons which may cover some of pixels from (`left`, `top`) to
+    /// (`right`, `bottom`) (exclusive), each once.
+    pub fn rect(
+        &self,
+        left: i32,
+        top: i32,
+        right: i32,
+        bottom: i32,
+    ) -> Vec<usize> {
+        let clamp = |v: i32, n: i32| (v / TILE).max(0).min(n);
+        let (c0, c1) =
+            (clamp(left, self.cols), clamp(right + TILE - 1, self.cols));
+        let (r0, r1) =
+            (clamp(top, self.rows), clamp(bottom + TILE - 1, self.rows));
+
+        let mut rect = Vec::new();
+        for r in r0..r1 {
+            for c in c0..c1 {
+                rect.extend_from_slice(
+                    &self.cells[(r * self.cols + c) as usize],
+                );
+            }
+        }
+        rect.sort_unstable();
+        rect.dedup();
+        rect
+    }
+
+    /// Memory taken by rows of cells owned only by this copy in bytes.
+    pub fn size_hint(&self) -> usize {
+        self.cells.unshared_size(|cell| {
+            std::mem::size_of::<Vec<usize>>()
+                + cell.capacity() * std::mem::size_of::<usize>()
+        })
+    }
+
+    /// Memory taken by all rows of cells in bytes.
+    pub fn size(&self) -> usize {
+        self.cells
+            .iter()
+            .map(|cell| {
+                std::mem::size_of::<Vec<usize>>()
+                    + cell.capacity() * std::mem::size_of::<usize>()
+            })
+            .sum()
+    }
+
+    /// Polygons which may cover (x, y).
+    pub fn candidates(&self, x: i32, y: i32) -> &[usize] {
+        let cell = (y / TILE) * self.cols + x / TILE;
+        &self.cells[cell as usize]
+    }
+
+    /// Polygons covering (x, y), in no particular order.
+    pub fn covering<'a>(
+        &'a self,
+        x: i32,
+        y: i32,
+        polygons: &'a CowVec<Polygon>,
+    ) -> impl Iterator<Item = &'a Polygon> + 'a {
+        let (width, height) = (self.width, self.height);
+
+        self.candidates(x, y)
+            .iter()
+            .map(move |i| &polygons[*i])
+            .filter(move |p| p.contains(x, y, width, height))
+    }
+}
@@ -0,0 +1,286 @@
+use super::randrange;
+use super::selection::Selection;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of a new optimization run, used to tell them apart in history.
+pub fn next_run() -> usize {
+    RUNS.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Statistics of one generation.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub run: String,
+    pub ngen: u64,
+    pub best: f64,
+    pub median: f64,
+    pub worst: f64,
+    /// Mean `DNA::distance` between pairs of individuals: 0 if all of them
+    /// are equal, 1 if every two of them differ in every polygon.
+    pub diversity: f64,
+    /// Population size.
+    pub mu: usize,
+    /// Mutation scale of the best individual.
+    pub scale: usize,
+    pub millis: u128,
+}
+
+impl Record {
+    const CSV_HEADER: &'static str =
+        "run,generation,best,median,worst,diversity,population,scale,millis";
+
+    fn csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{}",
+            self.run,
+            self.ngen,
+            self.best,
+            self.median,
+            self.worst,
+            self.diversity,
+            self.mu,
+            self.scale,
+            self.millis
+        )
+    }
+
+    fn json(&self) -> String {
+        format!(
+            concat!(
+                r#"{{"run": "{}", "generation": {}, "best": {}, "median": {}, "#,
+                r#""worst": {}, "diversity": {}, "population": {}, "#,
+                r#""scale": {}, "millis": {}}}"#
+            ),
+            self.run,
+            self.ngen,
+            self.best,
+            self.median,
+            self.worst,
+            self.diversity,
+            self.mu,
+            self.scale,
+            self.millis
+        )
+    }
+}
+
+/// Number of pairs of individuals sampled to estimate diversity.
+const PAIRS: usize = 45;
+
+///
+/// Mean distance between pairs of `items`: of all of them if there are at
+/// most `PAIRS`, of `PAIRS` pairs picked uniformly at random otherwise.
+///
+pub fn diversity<T>(items: &[T], distance: impl Fn(&T, &T) -> f64) -> f64 {
+    let n = items.len();
+    if n < 2 {
+        return 0.;
+    }
+
+    let total = n * (n - 1) / 2;
+    let pairs = if total <= PAIRS {
+        c![(i, j), for i in 0..n, for j in 0..n, if i < j]
+    } else {
+        c![
+            {
+                let i = randrange(0, n);
+                (i, (i + randrange(1, n)) % n)
+            },
+            for _i in 0..PAIRS
+        ]
+    };
+
+    pairs
+        .iter()
+        .map(|(i, j)| distance(&items[*i], &items[*j]))
+        .sum::<f64>()
+        / pairs.len() as f64
+}
+
+///
+/// Writes records of all runs into one file, as JSON lines if its extension
+/// is `.jsonl` or `.json` and as CSV otherwise.
+///
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+    json: bool,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let json = match path.as_ref().extension() {
+            Some(ext) => ext == "jsonl" || ext == "json",
+            None => false,
+        };
+        let mut file = File::create(path)?;
+        if !json {
+            writeln!(file, "{}", Record::CSV_HEADER)?;
+        }
+
+        Ok(Recorder {
+            file: Mutex::new(file),
+            json,
+        })
+    }
+
+    pub fn write(&self, record: &Record) {
+        let line = if self.json {
+            record.json()
+        } else {
+            record.csv()
+        };
+        writeln!(self.file.lock().unwrap(), "{}", line).unwrap();
+    }
+}
+
+///
+/// Best distinct individuals ever seen, sorted by fitness.
+///
+pub struct HallOfFame<DNA> {
+    size: usize,
+    entries: Vec<(f64, DNA)>,
+}
+
+impl<DNA> HallOfFame<DNA> {
+    pub fn new(size: usize) -> Self {
+        HallOfFame {
+            size,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn offer<A>(&mut self, fitness: f64, dna: &DNA)
+    where
+        A: Send + Sync + Clone,
+        DNA: Selection<A>,
+    {
+        let full = self.entries.len() >= self.size;
+        if self.size == 0 || (full && fitness >= self.entries.last().unwrap().0)
+        {
+            return;
+        }
+        let seen = self
+            .entries
+            .iter()
+            .any(|(f, other)| *f == fitness && other.distance(dna) == 0.);
+        if seen {
+            return;
+        }
+
+        let at = self
+            .entries
+            .iter()
+            .take_while(|(f, _)| *f <= fitness)
+            .count();
+        self.entries.insert(at, (fitness, dna.clone()));
+        self.entries.truncate(self.size);
+    }
+
+    ///
+    /// Save every entry into `dir` as `NN-FITNESS.png`.
+    ///
+    pub fn export<A, P>(&self, dir: P) -> io::Result<()>
+    where
+        A: Send + Sync + Clone,
+        DNA: Selection<A>,
+        P: AsRef<Path>,
+    {
+        std::fs::create_dir_all(&dir)?;
+
+        for (i, (fitness, dna)) in self.entries.iter().enumerate() {
+            let name = format!("{:02}-{:.0}.png", i, fitness);
+            dna.save(&dir.as_ref().join(name));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(ngen: u64) -> Record {
+        Record {
+            run: "colorize-0".to_string(),
+            ngen,
+            best: 1.5,
+            median: 2.,
+            worst: 3.,
+            diversity: 0.25,
+            mu: 4,
+            scale: 5,
+            millis: 6,
+        }
+    }
+
+    fn written(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "pido-history-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.write(&record(0));
+        recorder.write(&record(1));
+        drop(recorder);
+
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        text
+    }
+
+    #[test]
+    fn csv_history() {
+        assert_eq!(
+            written("run.csv"),
+            concat!(
+                "run,generation,best,median,worst,diversity,population,scale,millis\n",
+                "colorize-0,0,1.5,2,3,0.25,4,5,6\n",
+                "colorize-0,1,1.5,2,3,0.25,4,5,6\n",
+            )
+        );
+    }
+
+    #[test]
+    fn json_history() {
+        let text = written("run.jsonl");
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            concat!(
+                r#"{"run": "colorize-0", "generation": 1, "best": 1.5, "#,
+                r#""median": 2, "worst": 3, "diversity": 0.25, "#,
+                r#""population": 4, "scale": 5, "millis": 6}"#
+            )
+        );
+    }
+
+    #[test]
+    fn diversity_of_few_uses_all_pairs() {
+        let distance = |a: &f64, b: &f64| (a - b).abs();
+
+        assert_eq!(diversity(&[], distance), 0.);
+        assert_eq!(diversity(&[0.5], distance), 0.);
+        assert_eq!(diversity(&[0., 1., 3.], distance), 2.);
+    }
+
+    #[test]
+    fn diversity_samples_whole_population() {
+        let items = c![i, for i in 0..1000usize];
+
+        // Only pairs beyond the first ten individuals differ.
+        let distance =
+            |a: &usize, b: &usize| (*a >= 10 || *b >= 10) as u8 as f64;
+        assert!(diversity(&items, distance) > 0.5);
+        assert_eq!(diversity(&vec![7; 1000], |a, b| (a != b) as u8 as f64), 0.);
+    }
+}
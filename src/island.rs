@@ -0,0 +1,170 @@
+use super::history::{self, HallOfFame};
+use super::randrange;
+use super::selection::*;
+use rayon::iter::*;
+use std::time::Instant;
+
+/// Where migrants of an island go.
+#[derive(Debug, Clone, Copy)]
+pub enum Topology {
+    /// To the next island.
+    Ring,
+    /// To a random other island.
+    Random,
+}
+
+impl std::str::FromStr for Topology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ring" => Ok(Topology::Ring),
+            "random" => Ok(Topology::Random),
+            _ => Err(format!("unknown topology {:?}", s)),
+        }
+    }
+}
+
+///
+/// Independent populations evolving in parallel which exchange their best
+/// individuals every `migrate_every` generations.
+///
+#[derive(Debug, Clone)]
+pub struct Islands {
+    /// Settings of every island.
+    pub configs: Vec<Config>,
+    pub migrate_every: u64,
+    /// Number of best individuals sent by every island.
+    pub migrants: usize,
+    pub topology: Topology,
+}
+
+impl Islands {
+    fn destination(&self, from: usize) -> usize {
+        let n = self.configs.len();
+        match self.topology {
+            Topology::Ring => (from + 1) % n,
+            Topology::Random => (from + randrange(1, n)) % n,
+        }
+    }
+
+    fn migrate<Args, DNA>(&self, pops: &mut [Population<Args, DNA>])
+    where
+        Args: Send + Sync + Clone,
+        DNA: Selection<Args>,
+    {
+        let mut arrivals = c![Vec::new(), for _i in 0..pops.len()];
+
+        for (i, pop) in pops.iter().enumerate() {
+            let n = self.migrants.min(pop.gen.len());
+            arrivals[self.destination(i)].extend_from_slice(&pop.gen[..n]);
+        }
+        for (pop, migrants) in pops.iter_mut().zip(arrivals) {
+            pop.immigrate(migrants);
+        }
+    }
+
+    fn best<Args, DNA>(pops: &[Population<Args, DNA>]) -> &Mutation<Args, DNA>
+    where
+        Args: Send + Sync + Clone,
+        DNA: Selection<Args>,
+    {
+        pops.iter()
+            .map(|p| p.best())
+            .min_by(|a, b| a.fitness.total_cmp(&b.fitness))
+            .unwrap()
+    }
+
+    fn print_stats<Args, DNA>(pops: &[Population<Args, DNA>])
+    where
+        Args: Send + Sync + Clone,
+        DNA: Selection<Args>,
+    {
+        for (i, pop) in pops.iter().enumerate() {
+            println!(
+                "island {}: generation {} best {} worst {} population {}",
+                i,
+                pop.ngen,
+                pop.best().fitness,
+                pop.gen.last().unwrap().fitness,
+                pop.mu,
+            );
+        }
+    }
+
+    ///
+    /// Same as `Mutation::select`, but stagnation is judged by the best
+    /// individual over all islands.
+    ///
+    pub fn run<Args, DNA>(&self, args: Args, stop: Stop) -> DNA
+    where
+        Args: Send + Sync + Clone,
+        DNA: Selection<Args>,
+    {
+        let mut pops = self
+            .configs
+            .par_iter()
+            .map(|config| Population::<Args, DNA>::new(args.clone(), config))
+            .collect::<Vec<_>>();
+        let mut fails = 0;
+        let mut last_fitness = None;
+        let mut ngen = 0;
+        let run = history::next_run();
+        let mut hall = HallOfFame::new(self.configs[0].hall_of_fame);
+
+        loop {
+            ngen += 1;
+            let start = Instant::now();
+            pops.par_iter_mut()
+                .zip(&self.configs)
+                .for_each(|(pop, config)| pop.step(config));
+            for (i, (pop, config)) in pops.iter().zip(&self.configs).enumerate()
+            {
+                pop.observe(
+                    config,
+                    &format!("{}.{}", run, i),
+                    start,
+                    &mut hall,
+                );
+            }
+
+            if self.configs.len() > 1 && ngen % self.migrate_every.max(1) == 0 {
+                self.migrate(&mut pops);
+                Islands::print_stats(&pops);
+            }
+
+            let fitness = Islands::best(&pops).fitness;
+            if stop.stalled(last_fitness, fitness) {
+                let islands = pops.iter_mut().zip(&self.configs);
+                let mut settled = true;
+
+                for (pop, config) in islands {
+                    if config.adapt.is_none() {
+                        pop.grow(config);
+                    }
+                    settled &= pop.settled(config);
+                }
+                if settled {
+                    fails += 1;
+                }
+            } else {
+                fails = 0;
+            }
+            if fails == stop.retries {
+                break;
+            }
+            Islands::best(&pops).dna.print(ngen, fitness);
+            last_fitness = Some(fitness);
+
+            if stop.done(ngen) {
+                break;
+            }
+        }
+
+        Islands::print_stats(&pops);
+        if self.configs[0].hall_of_fame > 0 {
+            hall.export(format!("hall/run-{:03}", run)).unwrap();
+        }
+        Islands::best(&pops).dna.clone()
+    }
+}
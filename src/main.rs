@@ -1,17 +1,38 @@
 #[macro_use(c)]
 extern crate cute;
 
+mod anneal;
 mod colorize;
+mod cow;
 mod draw;
+mod edges;
+#[cfg(test)]
+mod fixtures;
+mod grid;
+mod history;
+mod island;
+mod nsga;
+mod palette;
+mod png;
 mod polygonize;
+mod prune;
+mod pyramid;
+mod rank;
+mod remote;
+mod render;
 mod selection;
+mod slic;
+mod strategy;
+mod svg;
+mod tile;
 
 use gperftools::profiler::PROFILER;
-use image;
 use rand::distributions::uniform::SampleUniform;
 use rand::prelude::*;
-use selection::Mutation;
+use selection::{Config, Mutation, Selection, Stop};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
 /// A basic example
@@ -22,68 +43,795 @@ struct Opt {
     #[structopt(short, long)]
     profile: bool,
 
+    /// Downscale factors of the resolution pyramid, coarsest first
+    #[structopt(long, default_value = "1", use_delimiter = true)]
+    levels: Vec<u32>,
+
+    /// Generations without improvement before a level stops (per level)
+    #[structopt(long, default_value = "2", use_delimiter = true)]
+    retries: Vec<i32>,
+
+    /// Maximum number of generations of a single run (per level)
+    #[structopt(long, use_delimiter = true)]
+    max_gens: Vec<u64>,
+
+    /// Improvements of fitness not bigger than this count as stagnation
+    #[structopt(long, default_value = "0")]
+    epsilon: f64,
+
+    /// Polygonize fitness penalty per extra polygon covering a pixel
+    #[structopt(long, default_value = "0")]
+    overlap_penalty: f64,
+
+    /// Weight of polygonize fitness term rewarding polygon sides along
+    /// image edges and penalizing polygons straddling them
+    #[structopt(long, default_value = "0")]
+    edge_weight: f64,
+
+    /// Edge detector for --edge-weight: "sobel" or "canny"
+    #[structopt(long, default_value = "sobel")]
+    edges: edges::Detector,
+
+    /// Low hysteresis threshold of --edges canny
+    #[structopt(long, default_value = "50")]
+    canny_low: f32,
+
+    /// High hysteresis threshold of --edges canny
+    #[structopt(long, default_value = "100")]
+    canny_high: f32,
+
+    /// Start from triangles seeded on about this many superpixels (SLIC)
+    /// colored with their mean colors instead of random ones
+    #[structopt(long)]
+    slic: Option<usize>,
+
+    /// SLIC compactness, bigger values give more regular superpixels
+    #[structopt(long, default_value = "10")]
+    compactness: f64,
+
+    /// Interpolate vertex colors over triangles instead of flat colors
+    #[structopt(long)]
+    gouraud: bool,
+
+    /// Start colorize from least squares fitted colors instead of random
+    #[structopt(long)]
+    fit: bool,
+
+    /// Quantize palette of the image to this number of colors
+    #[structopt(long)]
+    palette_size: Option<usize>,
+
+    /// Restrict colors to palette from GIMP .gpl file or list of hex colors
+    #[structopt(long, parse(from_os_str))]
+    palette: Option<PathBuf>,
+
+    /// Pin background color, e.g. "#ffffff"
+    #[structopt(long, parse(try_from_str = parse_color))]
+    background: Option<draw::Color>,
+
+    /// Initial range of local color mutations
+    #[structopt(long, default_value = "32")]
+    color_step: f64,
+
+    /// Decay of local color mutation range per generation
+    #[structopt(long, default_value = "0.95")]
+    step_decay: f64,
+
+    /// Share of color mutations picking random palette color instead of
+    /// shifting current one (always 1 with fixed or quantized palette)
+    #[structopt(long, default_value = "0.5")]
+    jump_ratio: f64,
+
+    /// Probability of making a child by crossover of two parents
+    #[structopt(long, default_value = "0")]
+    crossover_rate: f64,
+
+    /// Crossover kind: "spatial" or "uniform"
+    #[structopt(long, default_value = "spatial")]
+    crossover: draw::Crossover,
+
+    /// Image error kernel: "simd", "scalar" or "check" to run both and
+    /// compare results
+    #[structopt(long, default_value = "simd")]
+    kernel: render::Kernel,
+
+    /// Selection strategy: truncation, tournament[:SIZE], roulette, plus
+    /// (μ+λ) or comma (μ,λ)
+    #[structopt(long, default_value = "truncation", parse(try_from_str = strategy::parse))]
+    strategy: Arc<dyn strategy::Strategy>,
+
+    /// Population size
+    #[structopt(long, default_value = "10", parse(try_from_str = parse_positive))]
+    population: usize,
+
+    /// Number of children per generation (square of population size by
+    /// default)
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    offspring: Option<usize>,
+
+    /// Population never grows beyond this size
+    #[structopt(long)]
+    max_population: Option<usize>,
+
+    /// Upper limit of children per generation
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    max_offspring: Option<usize>,
+
+    /// Memory budget of a population in megabytes
+    #[structopt(long)]
+    memory_budget: Option<usize>,
+
+    /// Write statistics of every generation to this file (CSV, or JSON
+    /// lines for .jsonl)
+    #[structopt(long, parse(from_os_str))]
+    history: Option<PathBuf>,
+
+    /// Save this many best distinct individuals of every run into hall/
+    #[structopt(long, default_value = "0")]
+    hall_of_fame: usize,
+
+    /// Share of successful children above which mutations get bigger
+    #[structopt(long, default_value = "0.2")]
+    success_rate: f64,
+
+    /// Factor by which number of mutated polygons changes
+    #[structopt(long, default_value = "1.5")]
+    adapt_factor: f64,
+
+    /// Number of islands evolving in parallel
+    #[structopt(long, default_value = "1")]
+    islands: usize,
+
+    /// Selection strategies of islands, repeated if there are less of them
+    /// than islands (--strategy by default)
+    #[structopt(long, use_delimiter = true, parse(try_from_str = strategy::parse))]
+    island_strategies: Vec<Arc<dyn strategy::Strategy>>,
+
+    /// Generations between migrations
+    #[structopt(long, default_value = "10")]
+    migrate_every: u64,
+
+    /// Best individuals sent by an island on migration
+    #[structopt(long, default_value = "2")]
+    migrants: usize,
+
+    /// Migration topology: ring or random
+    #[structopt(long, default_value = "ring")]
+    topology: island::Topology,
+
+    /// Optimize both error and number of polygons on the last level (NSGA-II)
+    /// and save renders of the Pareto front into front/
+    #[structopt(long)]
+    pareto: bool,
+
+    /// Population size of --pareto, at least 2
+    #[structopt(long, default_value = "20", parse(try_from_str = parse_front_size))]
+    pareto_population: usize,
+
+    /// Probability of adding or removing polygons in --pareto mode
+    #[structopt(long, default_value = "0.5")]
+    structure_rate: f64,
+
+    /// Biggest share of polygons added or removed at once in --pareto mode
+    #[structopt(long, default_value = "0.1")]
+    structure_share: f64,
+
+    /// Refine colors with simulated annealing instead of selection
+    #[structopt(long)]
+    anneal: bool,
+
+    /// Initial annealing temperature
+    #[structopt(long, default_value = "100")]
+    temperature: f64,
+
+    /// Cooling schedule: exp:FACTOR, linear:STEP or adaptive:TARGET_RATE
+    #[structopt(long, default_value = "exp:0.95")]
+    cooling: anneal::Cooling,
+
+    /// Annealing mutations per sweep
+    #[structopt(long, default_value = "100")]
+    sweep: usize,
+
+    /// Annealing restarts from the best state
+    #[structopt(long, default_value = "0")]
+    restarts: u32,
+
+    /// Remove polygons whose removal increases error by less than this
+    #[structopt(long)]
+    prune_threshold: Option<f64>,
+
+    /// Remove the least useful polygons until this many are left
+    #[structopt(long)]
+    prune_to: Option<usize>,
+
+    /// Addresses of workers started with `pido worker --listen ADDR` to
+    /// evaluate colorize fitness on, comma separated
+    #[structopt(long, use_delimiter = true)]
+    workers: Vec<String>,
+
+    /// Split image into tiles of this size optimized independently
+    #[structopt(long)]
+    tile: Option<u32>,
+
+    /// Pixels by which tiles overlap their neighbours on every side
+    #[structopt(long, default_value = "16")]
+    tile_overlap: u32,
+
+    /// Number of tiles optimized, and kept in memory, at once
+    #[structopt(long, default_value = "1")]
+    resident: usize,
+
+    /// Also save result as SVG
+    #[structopt(long, parse(from_os_str))]
+    svg: Option<PathBuf>,
+
     /// Input image
     #[structopt(name = "IMAGE", parse(from_os_str))]
     input_image: PathBuf,
 }
 
+fn parse_color(s: &str) -> Result<draw::Color, String> {
+    draw::Color::from_hex(s).ok_or_else(|| format!("invalid color {:?}", s))
+}
+
+fn parse_positive(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_front_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n < 2 => Err("must be at least 2".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 pub fn randrange<T: SampleUniform>(l: T, h: T) -> T {
     rand::thread_rng().gen_range(l, h)
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    let polygons_number = 100_000;
-    let polygon_min = 6;
-    let polygon_max = 10;
-    let polygon_delta = 5;
+fn evolve<Args, DNA>(opt: &Opt, config: &Config, args: Args, stop: Stop) -> DNA
+where
+    Args: Send + Sync + Clone,
+    DNA: Selection<Args>,
+{
+    if opt.islands <= 1 {
+        return Mutation::<Args, DNA>::select(args, stop, config);
+    }
 
-    if opt.profile {
-        PROFILER.lock().unwrap().start("./my-prof.prof").unwrap();
+    let strategies = &opt.island_strategies;
+    let configs = c![
+        Config {
+            strategy: if strategies.is_empty() {
+                config.strategy.clone()
+            } else {
+                strategies[i % strategies.len()].clone()
+            },
+            ..config.clone()
+        },
+        for i in 0..opt.islands
+    ];
+
+    island::Islands {
+        configs,
+        migrate_every: opt.migrate_every,
+        migrants: opt.migrants,
+        topology: opt.topology,
+    }
+    .run(args, stop)
+}
+
+///
+/// Control of the number of mutated polygons between `min` and `max` by the
+/// 1/5 success rule.
+///
+fn adapt(opt: &Opt, min: usize, max: usize) -> selection::Adapt {
+    selection::Adapt {
+        target: opt.success_rate,
+        factor: opt.adapt_factor,
+        min,
+        max,
+    }
+}
+
+fn annealer(
+    opt: &Opt,
+    config: &Config,
+    adapt: selection::Adapt,
+) -> anneal::Annealer {
+    anneal::Annealer {
+        temperature: opt.temperature,
+        cooling: opt.cooling,
+        sweep: opt.sweep,
+        restarts: opt.restarts,
+        adapt: Some(adapt),
+        history: config.history.clone(),
+        hall_of_fame: config.hall_of_fame,
     }
+}
 
-    let ref_img = &image::open(opt.input_image).unwrap().to_rgb();
+fn colorize_args<'a>(
+    opt: &Opt,
+    ref_img: &'a image::RgbImage,
+    colors: &colorize::Colors,
+    polygons: Vec<draw::Polygon>,
+    scale_muts: usize,
+    snapshots: &str,
+) -> colorize::Args<'a> {
+    let jump_ratio = if opt.palette.is_some() || opt.palette_size.is_some() {
+        1.
+    } else {
+        opt.jump_ratio
+    };
 
-    let mut polygons = Mutation::<polygonize::Args, polygonize::DNA>::select(
-        polygonize::Args {
-            npolygons: polygons_number,
-            pol_size: (polygon_min, polygon_max),
-            pol_delta: polygon_delta,
-            scale_muts: 100,
-            width: 512,
-            height: 512,
-        },
-        2,
+    colorize::Args {
+        ref_img,
+        scale_muts,
+        polygons,
+        colors: colors.clone(),
+        gouraud: opt.gouraud,
+        color_step: opt.color_step,
+        step_decay: opt.step_decay,
+        jump_ratio,
+        crossover: opt.crossover,
+        kernel: opt.kernel,
+        snapshots: snapshots.to_string(),
+    }
+}
+
+///
+/// Connect to workers given by `--workers`, if any, to evaluate colorize
+/// fitness against `ref_img`.
+///
+fn remote(
+    opt: &Opt,
+    ref_img: &image::RgbImage,
+    colors: &colorize::Colors,
+) -> Option<Arc<remote::Pool>> {
+    if opt.workers.is_empty() {
+        return None;
+    }
+
+    let pool = remote::Pool::connect(
+        &opt.workers,
+        ref_img,
+        colors.get_bg(),
+        opt.kernel,
     )
-    .polygons;
-    colorize::generate_colors(&mut polygons, ref_img);
+    .unwrap();
+    Some(Arc::new(pool))
+}
 
-    let mut scale_muts = (polygons_number / 2) as usize;
+fn colorize(
+    opt: &Opt,
+    config: &Config,
+    ref_img: &image::RgbImage,
+    colors: &colorize::Colors,
+    polygons: Vec<draw::Polygon>,
+    stop: Stop,
+    snapshots: &str,
+) -> Vec<draw::Polygon> {
+    // Number of mutated polygons starts big and follows success rate.
+    let scale_muts = (polygons.len() / 2).max(1);
+    let adapt = adapt(opt, 4.min(scale_muts), scale_muts);
+    let config = &Config {
+        remote: remote(opt, ref_img, colors),
+        adapt: Some(adapt),
+        ..config.clone()
+    };
+    let args =
+        colorize_args(opt, ref_img, colors, polygons, scale_muts, snapshots);
 
-    while scale_muts > 4 {
-        let divide_rate = (scale_muts * 10) as u64;
-        eprintln!("\nmuts {}\n", scale_muts);
+    if opt.anneal {
+        annealer(opt, config, adapt)
+            .run::<_, colorize::DNA>(args, stop)
+            .polygons
+            .to_vec()
+    } else {
+        evolve::<_, colorize::DNA>(opt, config, args, stop)
+            .polygons
+            .to_vec()
+    }
+}
 
-        polygons = Mutation::<colorize::Args, colorize::DNA>::select(
-            colorize::Args {
-                ref_img,
+///
+/// Render every point of Pareto front into `front/` and list their
+/// objectives in `front/front.csv`.
+///
+fn save_front(
+    front: &[(Vec<draw::Polygon>, Vec<f64>)],
+    scale: i32,
+    width: u32,
+    height: u32,
+    bg: draw::Color,
+) {
+    std::fs::create_dir_all("front").unwrap();
+    let mut csv = String::from("n,error,polygons\n");
+
+    for (i, (polygons, objectives)) in front.iter().enumerate() {
+        let polygons = c![p.scale(scale, 1), for p in polygons];
+        render::draw_par(width, height, bg, &polygons)
+            .save_with_format(
+                format!("front/{:04}.png", i),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        csv += &format!("{},{},{}\n", i, objectives[0], objectives[1]);
+    }
+
+    std::fs::write("front/front.csv", csv).unwrap();
+}
+
+/// Pareto front as polygons of every point with their objectives.
+type Front = Vec<(Vec<draw::Polygon>, Vec<f64>)>;
+
+///
+/// Run all levels of the pyramid on `ref_img`. Returns polygons at its full
+/// resolution and, with `--pareto`, Pareto front of the last level together
+/// with its scale.
+///
+fn optimize(
+    opt: &Opt,
+    config: &Config,
+    polygonize_config: &Config,
+    colors: &colorize::Colors,
+    ref_img: &image::RgbImage,
+    polygons_number: i32,
+    snapshots: &str,
+) -> (Vec<draw::Polygon>, Front, i32) {
+    let polygon_min = 6;
+    let polygon_max = 10;
+    let polygon_delta = 5;
+    let levels =
+        pyramid::levels(&opt.levels, &opt.retries, &opt.max_gens, opt.epsilon);
+    let mut polygons: Vec<draw::Polygon> = Vec::new();
+    let mut polygons_number = polygons_number;
+    let mut prev_scale = None;
+
+    let mut front = Vec::new();
+
+    for (nlevel, level) in levels.iter().enumerate() {
+        let scale = level.scale as i32;
+        let level_img = &pyramid::downscale(ref_img, level.scale);
+        let (width, height) = level_img.dimensions();
+        eprintln!("\nlevel 1/{} {}x{}\n", scale, width, height);
+
+        if let Some(prev) = prev_scale {
+            polygons = c![p.scale(prev, scale), for p in &polygons];
+        }
+
+        let segments = match opt.slic {
+            Some(n) if prev_scale.is_none() => {
+                let segments = slic::slic(level_img, n, opt.compactness);
+                polygons = segments.seed();
+                // Seeds replace random polygons, none of them is dropped
+                // and no random ones are buried under them.
+                polygons_number = polygons.len() as i32;
+                eprintln!("slic: {} seed polygons", polygons.len());
+                Some(segments)
+            }
+            _ => None,
+        };
+        let edges = if opt.edge_weight != 0. {
+            Some(Arc::new(edges::EdgeMap::new(
+                level_img,
+                opt.edges,
+                (opt.canny_low, opt.canny_high),
+            )))
+        } else {
+            None
+        };
+        let pol_min = (polygon_min / scale).max(1);
+        let pol_size = (pol_min, (polygon_max / scale).max(pol_min + 1));
+        // Polygons surviving from the previous level keep their colors.
+        let kept = polygons.iter().map(vertices).collect::<HashSet<_>>();
+        polygons = evolve::<_, polygonize::DNA>(
+            opt,
+            polygonize_config,
+            polygonize::Args {
                 polygons,
-                scale_muts,
-                divide_rate,
+                npolygons: polygons_number,
+                pol_size,
+                pol_delta: (polygon_delta / scale).max(1),
+                scale_muts: 100,
+                width: width as i32,
+                height: height as i32,
+                crossover: opt.crossover,
+                overlap_penalty: opt.overlap_penalty,
+                edges,
+                edge_weight: opt.edge_weight,
             },
-            2,
+            level.stop,
         )
-        .polygons;
+        .polygons
+        .to_vec();
+        if opt.fit {
+            colorize::fit_colors(&mut polygons, level_img, colors, opt.gouraud);
+        } else if let Some(segments) = &segments {
+            segments.paint(&mut polygons, opt.gouraud, colors.get_bg());
+            polygons.iter_mut().for_each(|p| colors.restrict(p));
+        } else {
+            colorize::generate_colors(
+                polygons.iter_mut().filter(|p| !kept.contains(&vertices(p))),
+                colors,
+                opt.gouraud,
+            );
+        }
+
+        if opt.pareto && nlevel + 1 == levels.len() {
+            let nsga = nsga::Nsga {
+                population: opt.pareto_population,
+                structure_rate: opt.structure_rate,
+                structure_share: opt.structure_share,
+                history: config.history.clone(),
+                hall_of_fame: config.hall_of_fame,
+            };
+            let args =
+                colorize_args(opt, level_img, colors, polygons, 100, snapshots);
+
+            front = nsga
+                .run::<_, colorize::DNA>(args, level.stop)
+                .into_iter()
+                .map(|(dna, objectives)| (dna.polygons.to_vec(), objectives))
+                .collect();
+            polygons = front[0].0.clone();
+        } else {
+            polygons = colorize(
+                opt, config, level_img, colors, polygons, level.stop, snapshots,
+            );
+        }
+        prev_scale = Some(scale);
+    }
+
+    if let Some(prev) = prev_scale {
+        polygons = c![p.scale(prev, 1), for p in &polygons];
+    }
+
+    (polygons, front, prev_scale.unwrap_or(1))
+}
+
+/// Vertices of `p`, to tell polygons apart.
+fn vertices(p: &draw::Polygon) -> [(i32, i32); 3] {
+    let [a, b, c] = p.points;
+    [(a.x, a.y), (b.x, b.y), (c.x, c.y)]
+}
 
-        scale_muts /= 2;
+///
+/// `pido worker [--listen ADDR]`: evaluate fitness for other processes.
+///
+fn worker(args: &[String]) {
+    let addr = match args {
+        [] => "127.0.0.1:7878",
+        [flag, addr] if flag == "--listen" => addr,
+        _ => {
+            eprintln!("usage: pido worker [--listen ADDR]");
+            std::process::exit(1);
+        }
+    };
+
+    let listener = std::net::TcpListener::bind(addr).unwrap();
+    remote::serve(listener).unwrap();
+}
+
+///
+/// Colors of `img`: restricted to `--palette`, quantized to
+/// `--palette-size` or all of them.
+///
+fn colors(opt: &Opt, img: &image::RgbImage) -> colorize::Colors {
+    match &opt.palette {
+        Some(path) => {
+            colorize::Colors::from_palette(img, palette::load(path).unwrap())
+        }
+        None => colorize::Colors::new(img, opt.palette_size),
+    }
+}
+
+/// Polygons, size of the image, background and palette. The result is
+/// rendered into `output.png`.
+type Output = (
+    Vec<draw::Polygon>,
+    (u32, u32),
+    draw::Color,
+    Option<Vec<(draw::Color, u32)>>,
+);
+
+///
+/// Optimize the whole image at once, with Pareto front if asked for.
+///
+fn whole(
+    opt: &Opt,
+    config: &Config,
+    polygonize_config: &Config,
+    polygons_number: i32,
+) -> Output {
+    let ref_img = &image::open(&opt.input_image).unwrap().to_rgb();
+    let mut colors = colors(opt, ref_img);
+    if let Some(bg) = opt.background {
+        colors = colors.with_bg(bg);
     }
+    let (width, height) = ref_img.dimensions();
+
+    let (mut polygons, front, front_scale) = optimize(
+        opt,
+        config,
+        polygonize_config,
+        &colors,
+        ref_img,
+        polygons_number,
+        "./img/",
+    );
 
-    let black = draw::Color(image::Rgb([0, 0, 0]));
-    let image = colorize::polygons_draw(512, 512, black, polygons);
+    let bg = colors.get_bg();
+    if opt.prune_threshold.is_some() || opt.prune_to.is_some() {
+        let (pruned, report) = prune::prune(
+            polygons,
+            ref_img,
+            bg,
+            opt.prune_threshold,
+            opt.prune_to,
+        );
+        println!("{}", report);
+        polygons = pruned;
+    }
+    if !front.is_empty() {
+        save_front(&front, front_scale, width, height, bg);
+    }
 
-    image
+    render::draw_par(width, height, bg, &polygons)
         .save_with_format("output.png", image::ImageFormat::Png)
         .unwrap();
+    let palette = colors.palette().map(<[_]>::to_vec);
+    (polygons, (width, height), bg, palette)
+}
+
+///
+/// Optimize the image tile by tile. Tiles are decoded, colors of them are
+/// collected, they are pruned and rendered one batch of `--resident` tiles
+/// at a time, so the whole reference image is never kept in memory.
+///
+fn tiled(
+    opt: &Opt,
+    config: &Config,
+    polygonize_config: &Config,
+    size: u32,
+    polygons_number: i32,
+) -> Output {
+    let source = tile::Source::open(&opt.input_image).unwrap();
+    let (width, height) = source.dimensions();
+    let tiles = tile::tiles(width, height, size, opt.tile_overlap);
+    let area = width as f64 * height as f64;
+
+    let bg = match opt.background {
+        Some(bg) => bg,
+        None => {
+            let dominant = source.dominant().unwrap();
+            match &opt.palette {
+                Some(path) => {
+                    let palette =
+                        c![(c, 0), for c in palette::load(path).unwrap()];
+                    palette[palette::nearest(&palette, dominant)].0
+                }
+                None => dominant,
+            }
+        }
+    };
+    let palettes = Mutex::new(HashMap::new());
+    let reports = Mutex::new(Vec::new());
+
+    let polygons = tile::run(&source, &tiles, opt.resident, |t, tile_img| {
+        let share = t.width as f64 * t.height as f64 / area;
+        let n = (polygons_number as f64 * share).max(1.);
+        let colors = colors(opt, &tile_img).with_bg(bg);
+
+        let mut polygons = optimize(
+            opt,
+            config,
+            polygonize_config,
+            &colors,
+            &tile_img,
+            n as i32,
+            // Tiles run in parallel, snapshots of each go apart.
+            &format!("./img/tile-{}-{}-", t.x, t.y),
+        )
+        .0;
+        if opt.prune_threshold.is_some() || opt.prune_to.is_some() {
+            let target = opt
+                .prune_to
+                .map(|n| ((n as f64 * share).round() as usize).max(1));
+            let (pruned, report) = prune::prune(
+                polygons,
+                &tile_img,
+                bg,
+                opt.prune_threshold,
+                target,
+            );
+            reports.lock().unwrap().push(report);
+            polygons = pruned;
+        }
+        if let Some(palette) = colors.palette() {
+            let mut palettes = palettes.lock().unwrap();
+            for (color, n) in palette {
+                *palettes.entry(*color).or_insert(0) += n;
+            }
+        }
+        polygons
+    })
+    .unwrap();
+
+    let reports = reports.into_inner().unwrap();
+    if !reports.is_empty() {
+        println!("{}", prune::Report::sum(&reports));
+    }
+    let mut palette = palettes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .collect::<Vec<_>>();
+    palette.sort_by_key(|(c, n)| (std::cmp::Reverse(*n), (c.0).0));
+    let palette = if opt.palette.is_some() || opt.palette_size.is_some() {
+        Some(palette)
+    } else {
+        None
+    };
+
+    let output =
+        std::io::BufWriter::new(std::fs::File::create("output.png").unwrap());
+    tile::render(&tiles, opt.resident, (width, height), bg, &polygons, output)
+        .unwrap();
+    (polygons, (width, height), bg, palette)
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("worker") {
+        return worker(&args[2..]);
+    }
+
+    let opt = Opt::from_args();
+    let polygons_number = 100_000;
+
+    if opt.profile {
+        PROFILER.lock().unwrap().start("./my-prof.prof").unwrap();
+    }
+
+    let config = Config {
+        crossover: opt.crossover_rate,
+        strategy: opt.strategy.clone(),
+        population: opt.population,
+        offspring: opt.offspring,
+        adapt: None,
+        max_population: opt.max_population,
+        max_offspring: opt.max_offspring,
+        memory_budget: opt.memory_budget.map(|mb| mb << 20),
+        history: opt
+            .history
+            .as_ref()
+            .map(|path| Arc::new(history::Recorder::create(path).unwrap())),
+        hall_of_fame: opt.hall_of_fame,
+        remote: None,
+    };
+    let polygonize_config = Config {
+        adapt: Some(adapt(&opt, 1, polygons_number as usize / 2)),
+        ..config.clone()
+    };
+
+    let (polygons, (width, height), bg, palette) = match opt.tile {
+        Some(size) => {
+            tiled(&opt, &config, &polygonize_config, size, polygons_number)
+        }
+        None => whole(&opt, &config, &polygonize_config, polygons_number),
+    };
+
+    if let Some(path) = &opt.svg {
+        svg::save(path, width, height, bg, &polygons).unwrap();
+    }
+    if let Some(palette) = palette {
+        palette::save_json("output.json", &palette, &polygons).unwrap();
+    }
 
     if opt.profile {
         PROFILER.lock().unwrap().stop().unwrap();
@@ -0,0 +1,283 @@
+use super::history::{self, HallOfFame, Record, Recorder};
+use super::randrange;
+use super::selection::{Selection, Stop};
+use rayon::iter::*;
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+///
+/// Individual optimized for several objectives at once, all of them are
+/// minimized.
+///
+pub trait MultiObjective<A: Send + Sync + Clone>: Selection<A> {
+    fn objectives(&self) -> Vec<f64>;
+    /// Number of primitives.
+    fn size(&self) -> usize;
+    /// Add `delta` primitives, or remove them if it is negative.
+    fn mutate_structure(&self, delta: i64) -> Self;
+}
+
+struct Individual<DNA> {
+    dna: DNA,
+    objectives: Vec<f64>,
+    rank: usize,
+    crowding: f64,
+}
+
+///
+/// NSGA-II: elitist multi-objective evolution keeping the population spread
+/// along the Pareto front.
+///
+#[derive(Debug, Clone)]
+pub struct Nsga {
+    pub population: usize,
+    /// Probability of structural mutation of a child.
+    pub structure_rate: f64,
+    /// Biggest share of primitives added or removed by structural mutation.
+    pub structure_share: f64,
+    /// Record every generation here if set, fitness is the first
+    /// objective.
+    pub history: Option<Arc<Recorder>>,
+    /// Save this many individuals with the best first objective into
+    /// `hall/run-NNN/`.
+    pub hall_of_fame: usize,
+}
+
+fn dominates(a: &[f64], b: &[f64]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a <= b) && a.iter().zip(b).any(|(a, b)| a < b)
+}
+
+///
+/// Split population into fronts of mutually non-dominated individuals and
+/// set their ranks.
+///
+fn sort_fronts<DNA>(pop: &mut [Individual<DNA>]) -> Vec<Vec<usize>> {
+    let n = pop.len();
+    let mut dominated = vec![Vec::new(); n];
+    let mut count = vec![0; n];
+    let mut fronts = vec![Vec::new()];
+
+    for i in 0..n {
+        for j in 0..n {
+            if dominates(&pop[i].objectives, &pop[j].objectives) {
+                dominated[i].push(j);
+            } else if dominates(&pop[j].objectives, &pop[i].objectives) {
+                count[i] += 1;
+            }
+        }
+        if count[i] == 0 {
+            pop[i].rank = 0;
+            fronts[0].push(i);
+        }
+    }
+
+    let mut rank = 0;
+    while !fronts[rank].is_empty() {
+        let mut next = Vec::new();
+        for &i in &fronts[rank] {
+            for &j in &dominated[i] {
+                count[j] -= 1;
+                if count[j] == 0 {
+                    pop[j].rank = rank + 1;
+                    next.push(j);
+                }
+            }
+        }
+        rank += 1;
+        fronts.push(next);
+    }
+    fronts.pop();
+
+    fronts
+}
+
+fn set_crowding<DNA>(pop: &mut [Individual<DNA>], front: &[usize]) {
+    for &i in front {
+        pop[i].crowding = 0.;
+    }
+
+    for m in 0..pop[front[0]].objectives.len() {
+        let mut sorted = front.to_vec();
+        sorted.sort_by(|a, b| {
+            pop[*a].objectives[m].total_cmp(&pop[*b].objectives[m])
+        });
+
+        let (first, last) = (sorted[0], sorted[sorted.len() - 1]);
+        let range = pop[last].objectives[m] - pop[first].objectives[m];
+        pop[first].crowding = f64::INFINITY;
+        pop[last].crowding = f64::INFINITY;
+        if range <= 0. {
+            continue;
+        }
+
+        for k in 1..sorted.len().saturating_sub(1) {
+            let gap = pop[sorted[k + 1]].objectives[m]
+                - pop[sorted[k - 1]].objectives[m];
+            pop[sorted[k]].crowding += gap / range;
+        }
+    }
+}
+
+/// Lower rank wins, then bigger crowding distance.
+fn better<DNA>(a: &Individual<DNA>, b: &Individual<DNA>) -> bool {
+    match a.rank.cmp(&b.rank) {
+        Ordering::Less => true,
+        Ordering::Greater => false,
+        Ordering::Equal => a.crowding > b.crowding,
+    }
+}
+
+impl Nsga {
+    fn individual<A, DNA>(dna: DNA) -> Individual<DNA>
+    where
+        A: Send + Sync + Clone,
+        DNA: MultiObjective<A>,
+    {
+        Individual {
+            objectives: dna.objectives(),
+            dna,
+            rank: 0,
+            crowding: 0.,
+        }
+    }
+
+    fn child<A, DNA>(&self, pop: &[Individual<DNA>]) -> Individual<DNA>
+    where
+        A: Send + Sync + Clone,
+        DNA: MultiObjective<A>,
+    {
+        let (a, b) = (randrange(0, pop.len()), randrange(0, pop.len()));
+        let parent = if better(&pop[a], &pop[b]) { a } else { b };
+
+        let mut dna = pop[parent].dna.mutate();
+        if randrange(0., 1.) < self.structure_rate {
+            let most = (dna.size() as f64 * self.structure_share) as i64;
+            let delta = randrange(1, most.max(1) + 1);
+            dna = dna.mutate_structure(if randrange(0, 2) == 0 {
+                -delta
+            } else {
+                delta
+            });
+        }
+        Nsga::individual(dna)
+    }
+
+    ///
+    /// Record statistics of the first objective of a generation into
+    /// history and offer its individuals to hall of fame.
+    ///
+    fn observe<A, DNA>(
+        &self,
+        pop: &[Individual<DNA>],
+        run: &str,
+        ngen: u64,
+        start: Instant,
+        hall: &mut HallOfFame<DNA>,
+    ) where
+        A: Send + Sync + Clone,
+        DNA: MultiObjective<A>,
+    {
+        for p in pop {
+            hall.offer(p.objectives[0], &p.dna);
+        }
+
+        let recorder = match &self.history {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let mut sorted = c![p, for p in pop];
+        sorted.sort_by(|a, b| a.objectives[0].total_cmp(&b.objectives[0]));
+        recorder.write(&Record {
+            run: run.to_string(),
+            ngen,
+            best: sorted[0].objectives[0],
+            median: sorted[sorted.len() / 2].objectives[0],
+            worst: sorted[sorted.len() - 1].objectives[0],
+            diversity: history::diversity(&sorted, |a, b| {
+                a.dna.distance(&b.dna)
+            }),
+            mu: pop.len(),
+            scale: sorted[0].dna.scale(),
+            millis: start.elapsed().as_millis(),
+        });
+    }
+
+    ///
+    /// Evolve population for `stop.max_gens` generations (100 if not set)
+    /// and return its Pareto front sorted by the first objective. Initial
+    /// individuals are spread evenly between all primitives of generated
+    /// DNA and a `1 / population` share of them.
+    ///
+    pub fn run<A, DNA>(&self, args: A, stop: Stop) -> Vec<(DNA, Vec<f64>)>
+    where
+        A: Send + Sync + Clone,
+        DNA: MultiObjective<A>,
+    {
+        let parent = DNA::generate(args);
+        let n = parent.size();
+        let mut pop = (0..self.population)
+            .into_par_iter()
+            .map(|i| {
+                let removed = n * i / self.population;
+                Nsga::individual(
+                    parent.mutate_structure(-(removed as i64)).mutate(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for front in sort_fronts(&mut pop) {
+            set_crowding(&mut pop, &front);
+        }
+        let run = history::next_run();
+        let mut hall = HallOfFame::new(self.hall_of_fame);
+
+        for ngen in 1..=stop.max_gens.unwrap_or(100) {
+            let start = Instant::now();
+            let kids = (0..self.population)
+                .into_par_iter()
+                .map(|_| self.child(&pop))
+                .collect::<Vec<_>>();
+            pop.extend(kids);
+
+            let mut next = Vec::with_capacity(self.population);
+            for mut front in sort_fronts(&mut pop) {
+                set_crowding(&mut pop, &front);
+                if next.len() + front.len() > self.population {
+                    front.sort_by(|a, b| {
+                        pop[*b].crowding.total_cmp(&pop[*a].crowding)
+                    });
+                    front.truncate(self.population - next.len());
+                }
+                next.extend(front);
+                if next.len() == self.population {
+                    break;
+                }
+            }
+
+            next.sort_unstable();
+            let mut i = 0;
+            pop.retain(|_| {
+                i += 1;
+                next.binary_search(&(i - 1)).is_ok()
+            });
+
+            self.observe(&pop, &run.to_string(), ngen, start, &mut hall);
+
+            let front = c![&p.objectives, for p in &pop, if p.rank == 0];
+            println!(
+                "nsga: generation {} front {} best {:?}",
+                ngen,
+                front.len(),
+                front.iter().min_by(|a, b| a[0].total_cmp(&b[0])).unwrap()
+            );
+        }
+
+        if self.hall_of_fame > 0 {
+            hall.export(format!("hall/run-{:03}", run)).unwrap();
+        }
+        let mut front = c![(p.dna, p.objectives), for p in pop, if p.rank == 0];
+        front.sort_by(|a, b| a.1[0].total_cmp(&b.1[0]));
+        front.dedup_by(|a, b| a.1 == b.1);
+        front
+    }
+}
@@ -0,0 +1,296 @@
+use super::draw::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+/// Box of colors with their pixel counts for median cut.
+struct Bucket(Vec<(Color, u32)>);
+
+impl Bucket {
+    fn channel(c: &Color, ch: usize) -> u8 {
+        (c.0).0[ch]
+    }
+
+    /// Channel with the widest range and that range.
+    fn widest(&self) -> (usize, u8) {
+        let mut best = (0, 0);
+
+        for ch in 0..3 {
+            let it = self.0.iter().map(|(c, _)| Bucket::channel(c, ch));
+            let range = it.clone().max().unwrap() - it.min().unwrap();
+            if range > best.1 {
+                best = (ch, range);
+            }
+        }
+        best
+    }
+
+    fn split(mut self) -> (Bucket, Bucket) {
+        let (ch, _) = self.widest();
+        self.0.sort_by_key(|(c, _)| Bucket::channel(c, ch));
+
+        let total: u64 = self.0.iter().map(|(_, n)| *n as u64).sum();
+        let mut acc = 0;
+        let mut at = self.0.len() - 1;
+        for (i, (_, n)) in self.0.iter().enumerate() {
+            acc += *n as u64;
+            if acc * 2 >= total {
+                at = i + 1;
+                break;
+            }
+        }
+        let at = at.max(1).min(self.0.len() - 1);
+        let rest = self.0.split_off(at);
+
+        (self, Bucket(rest))
+    }
+
+    fn average(&self) -> (Color, u32) {
+        let mut sum = [0u64; 3];
+        let mut total = 0u64;
+
+        for (c, n) in &self.0 {
+            for (ch, s) in sum.iter_mut().enumerate() {
+                *s += Bucket::channel(c, ch) as u64 * *n as u64;
+            }
+            total += *n as u64;
+        }
+
+        let avg = |s: u64| ((s + total / 2) / total) as u8;
+        let color = Color(image::Rgb([avg(sum[0]), avg(sum[1]), avg(sum[2])]));
+        (color, total as u32)
+    }
+}
+
+///
+/// Reduce histogram of colors to at most `size` colors with median cut.
+/// Counts of resulting colors are sums of counts of merged ones.
+///
+pub fn median_cut(hist: Vec<(Color, u32)>, size: usize) -> Vec<(Color, u32)> {
+    if hist.len() <= size {
+        return hist;
+    }
+
+    let mut buckets = vec![Bucket(hist)];
+    while buckets.len() < size {
+        let widest = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.0.len() > 1)
+            .max_by_key(|(_, b)| b.widest().1)
+            .map(|(i, _)| i);
+        let i = match widest {
+            Some(i) => i,
+            None => break,
+        };
+
+        let (a, b) = buckets.swap_remove(i).split();
+        buckets.push(a);
+        buckets.push(b);
+    }
+
+    c![b.average(), for b in &buckets]
+}
+
+///
+/// Index of the palette color nearest to `color`.
+///
+pub fn nearest(palette: &[(Color, u32)], color: Color) -> usize {
+    let image::Rgb(a) = color.0;
+    let dist = |c: &Color| {
+        let image::Rgb(b) = c.0;
+        c![(a[i] as i32 - b[i] as i32).pow(2), for i in 0..3]
+            .iter()
+            .sum::<i32>()
+    };
+
+    (0..palette.len())
+        .min_by_key(|i| dist(&palette[*i].0))
+        .unwrap()
+}
+
+///
+/// Load palette from GIMP palette (.gpl) file or plain list of hex colors,
+/// one per line.
+///
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<Color>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+/// Parse contents of a palette file, see `load`.
+pub fn parse(text: &str) -> io::Result<Vec<Color>> {
+    let invalid = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid palette line: {:?}", line),
+        )
+    };
+    let gpl = text.starts_with("GIMP Palette");
+    let mut colors = Vec::new();
+
+    for line in text.lines().skip(if gpl { 1 } else { 0 }) {
+        let line = line.trim();
+        if line.is_empty()
+            || (gpl && line.starts_with('#'))
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+
+        let color = if gpl {
+            let rgb = c![c.parse::<u8>().ok(), for c in line.split_whitespace().take(3)];
+            match rgb.as_slice() {
+                [Some(r), Some(g), Some(b)] => Color(image::Rgb([*r, *g, *b])),
+                _ => return Err(invalid(line)),
+            }
+        } else {
+            Color::from_hex(line).ok_or_else(|| invalid(line))?
+        };
+        colors.push(color);
+    }
+
+    if colors.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "empty palette",
+        ));
+    }
+    Ok(colors)
+}
+
+///
+/// Number of polygons (or polygon vertices for Gouraud shaded ones) using
+/// each palette color.
+///
+pub fn usage(palette: &[(Color, u32)], polygons: &[Polygon]) -> Vec<u32> {
+    let index: HashMap<Color, usize> = palette
+        .iter()
+        .enumerate()
+        .map(|(i, (c, _))| (*c, i))
+        .collect();
+    let mut usage = vec![0; palette.len()];
+    let mut count = |color: Color| {
+        if let Some(i) = index.get(&color) {
+            usage[*i] += 1;
+        }
+    };
+
+    for p in polygons {
+        match p.vertex_colors {
+            Some(colors) => colors.iter().for_each(|c| count(*c)),
+            None => count(p.color),
+        }
+    }
+    usage
+}
+
+///
+/// Save palette with pixel counts and usage counts as JSON.
+///
+pub fn save_json<P: AsRef<Path>>(
+    path: P,
+    palette: &[(Color, u32)],
+    polygons: &[Polygon],
+) -> io::Result<()> {
+    let usage = usage(palette, polygons);
+    let mut s = String::from("{\"palette\": [\n");
+
+    for (i, ((color, pixels), used)) in palette.iter().zip(&usage).enumerate() {
+        let sep = if i + 1 == palette.len() { "" } else { "," };
+        writeln!(
+            s,
+            "  {{\"color\": \"{}\", \"pixels\": {}, \"polygons\": {}}}{}",
+            color.hex(),
+            pixels,
+            used,
+            sep
+        )
+        .unwrap();
+    }
+    s.push_str("]}\n");
+
+    std::fs::write(path, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color(image::Rgb([r, g, b]))
+    }
+
+    #[test]
+    fn parse_gpl() {
+        let text = "GIMP Palette\nName: Brand\nColumns: 4\n#\n\
+                    # comment\n  0   0   0\tBlack\n255 128  7 Orange\n\n";
+        assert_eq!(parse(text).unwrap(), vec![rgb(0, 0, 0), rgb(255, 128, 7)]);
+    }
+
+    #[test]
+    fn parse_hex() {
+        let text = "#000000\nff8007\n\n  #FFFFFF  \n";
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![rgb(0, 0, 0), rgb(255, 128, 7), rgb(255, 255, 255)]
+        );
+    }
+
+    #[test]
+    fn parse_bad_lines() {
+        assert!(parse("GIMP Palette\n0 0\n").is_err());
+        assert!(parse("GIMP Palette\n0 0 256\n").is_err());
+        assert!(parse("GIMP Palette\nzero 0 0\n").is_err());
+        assert!(parse("#00000\n").is_err());
+        assert!(parse("#0000000\n").is_err());
+        assert!(parse("#00000g\n").is_err());
+        assert!(parse("#000000\n0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert!(parse("").is_err());
+        assert!(parse("\n  \n").is_err());
+        assert!(parse("GIMP Palette\nName: Empty\n# nothing\n").is_err());
+    }
+
+    #[test]
+    fn median_cut_keeps_small_histograms() {
+        let hist = vec![(rgb(0, 0, 0), 3), (rgb(255, 0, 0), 1)];
+        assert_eq!(median_cut(hist.clone(), 2), hist);
+        assert_eq!(median_cut(hist.clone(), 8), hist);
+    }
+
+    #[test]
+    fn median_cut_limits_size_and_keeps_counts() {
+        let hist = c![
+            (rgb(i as u8, (i * 7) as u8, (255 - i) as u8), i + 1),
+            for i in 0..200u32
+        ];
+        let total: u32 = hist.iter().map(|(_, n)| n).sum();
+
+        for size in &[1, 2, 5, 16, 199] {
+            let palette = median_cut(hist.clone(), *size);
+            assert_eq!(palette.len(), *size);
+            assert_eq!(palette.iter().map(|(_, n)| n).sum::<u32>(), total);
+        }
+    }
+
+    #[test]
+    fn median_cut_separates_clusters() {
+        let mut hist = Vec::new();
+        for d in 0..4 {
+            hist.push((rgb(10 + d, 20 + d, 30 + d), 10));
+            hist.push((rgb(200 + d, 210 + d, 220 + d), 10));
+        }
+
+        let mut palette = median_cut(hist, 2);
+        palette.sort_by_key(|(c, _)| (c.0).0);
+        assert_eq!(
+            palette,
+            vec![(rgb(12, 22, 32), 40), (rgb(202, 212, 222), 40)]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::io::{self, Write};
+
+/// Compressed data collected before it is written as an IDAT chunk.
+const CHUNK: usize = 1 << 16;
+
+/// Longest back reference of deflate.
+const MAX_MATCH: usize = 258;
+
+///
+/// RGB PNG image written row by row, from the top down, so that only one
+/// row has to be kept in memory. Rows are compressed with a single fixed
+/// Huffman deflate block, runs of a repeated pixel become back references.
+///
+pub struct Writer<W: Write> {
+    out: W,
+    width: u32,
+    rows: u32,
+    crc: [u32; 256],
+    adler: (u32, u32),
+    bits: u64,
+    nbits: u32,
+    data: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W, width: u32, height: u32) -> io::Result<Self> {
+        let mut crc = [0; 256];
+        for (n, crc) in crc.iter_mut().enumerate() {
+            *crc = (0..8).fold(n as u32, |c, _| {
+                if c & 1 == 1 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                }
+            });
+        }
+
+        let mut writer = Writer {
+            out,
+            width,
+            rows: height,
+            crc,
+            adler: (1, 0),
+            bits: 0,
+            nbits: 0,
+            // Zlib header, no preset dictionary.
+            data: vec![0x78, 0x01],
+        };
+        writer.out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        // 8 bits per sample, RGB, deflate, adaptive filters, no interlace.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        writer.chunk(b"IHDR", &header)?;
+
+        // The only block, final and compressed with fixed codes.
+        writer.push(0b011, 3);
+        Ok(writer)
+    }
+
+    fn chunk(&mut self, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let crc = kind.iter().chain(data).fold(!0u32, |c, b| {
+            self.crc[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8)
+        });
+
+        self.out.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.out.write_all(kind)?;
+        self.out.write_all(data)?;
+        self.out.write_all(&(!crc).to_be_bytes())
+    }
+
+    /// Append `n` bits of `value` to the stream, least significant first.
+    fn push(&mut self, value: u32, n: u32) {
+        self.bits |= (value as u64) << self.nbits;
+        self.nbits += n;
+        while self.nbits >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    /// Append a Huffman code, these go most significant bit first.
+    fn code(&mut self, code: u32, n: u32) {
+        self.push(code.reverse_bits() >> (32 - n), n);
+    }
+
+    /// Fixed code of a literal, length or end of block symbol.
+    fn symbol(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    /// Back reference `len` bytes long to the previous pixel.
+    fn repeat(&mut self, len: usize) {
+        let len = len as u32;
+        let (symbol, extra, base) = match len {
+            3..=10 => (257 + len - 3, 0, len),
+            258 => (285, 0, 258),
+            _ => {
+                // Groups of four codes with one more extra bit each.
+                let extra = 31 - (len - 3).leading_zeros() - 2;
+                let group = (len - 3) >> extra;
+                let base = (group << extra) + 3;
+                (265 + (extra - 1) * 4 + group - 4, extra, base)
+            }
+        };
+
+        self.symbol(symbol);
+        self.push(len - base, extra);
+        // Distance 3 has code 2 and no extra bits.
+        self.code(2, 5);
+    }
+
+    fn checksum(&mut self, data: &[u8]) {
+        let (mut a, mut b) = self.adler;
+        for chunk in data.chunks(5552) {
+            for v in chunk {
+                a += *v as u32;
+                b += a;
+            }
+            a %= 65521;
+            b %= 65521;
+        }
+        self.adler = (a, b);
+    }
+
+    /// Write the next row of `width` pixels as raw RGB.
+    pub fn write_row(&mut self, row: &[u8]) -> io::Result<()> {
+        assert_eq!(row.len(), 3 * self.width as usize);
+        assert!(self.rows > 0, "all rows are written");
+        self.rows -= 1;
+
+        // No filter.
+        self.checksum(&[0]);
+        self.checksum(row);
+        self.symbol(0);
+
+        let mut i = 0;
+        while i < row.len() {
+            let len = if i < 3 {
+                0
+            } else {
+                (i..row.len().min(i + MAX_MATCH))
+                    .take_while(|j| row[*j] == row[j - 3])
+                    .count()
+            };
+            if len >= 3 {
+                self.repeat(len);
+                i += len;
+            } else {
+                self.symbol(row[i] as u32);
+                i += 1;
+            }
+        }
+
+        if self.data.len() >= CHUNK {
+            let data = std::mem::take(&mut self.data);
+            self.chunk(b"IDAT", &data)?;
+        }
+        Ok(())
+    }
+
+    /// End the image after its last row and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        assert_eq!(self.rows, 0, "rows are missing");
+
+        self.symbol(256);
+        self.push(0, (8 - self.nbits % 8) % 8);
+        let (a, b) = self.adler;
+        self.data.extend_from_slice(&(b << 16 | a).to_be_bytes());
+
+        let data = std::mem::take(&mut self.data);
+        self.chunk(b"IDAT", &data)?;
+        self.chunk(b"IEND", &[])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(img: &image::RgbImage) -> image::RgbImage {
+        let mut png =
+            Writer::new(Vec::new(), img.width(), img.height()).unwrap();
+        for row in img.chunks(3 * img.width() as usize) {
+            png.write_row(row).unwrap();
+        }
+        let png = png.finish().unwrap();
+        image::load_from_memory(&png).unwrap().to_rgb()
+    }
+
+    #[test]
+    fn decoded_rows_match() {
+        let noise = image::RgbImage::from_fn(37, 23, |_, _| {
+            image::Rgb([rand::random(), rand::random(), rand::random()])
+        });
+        assert_eq!(*encoded(&noise), *noise);
+
+        // Runs of every length, longer than the longest back reference too.
+        let runs = image::RgbImage::from_fn(1000, 40, |x, y| {
+            let v = (x / (y + 1)) as u8;
+            image::Rgb([v, v / 2, 7])
+        });
+        assert_eq!(*encoded(&runs), *runs);
+
+        let big = image::RgbImage::from_pixel(300, 300, image::Rgb([1, 2, 3]));
+        assert_eq!(*encoded(&big), *big);
+    }
+}
@@ -1,150 +1,408 @@
-use super::{draw::*, selection::*};
+use super::{
+    cow::CowVec, draw::*, edges::EdgeMap, grid::Grid, rank::Ranking,
+    selection::*,
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
+/// Ranking scores are fixed point with this many units per pixel, so that
+/// fractional edge terms count.
+const UNIT: f64 = 256.;
+/// Offset keeping ranking scores positive with negative edge terms.
+const BIAS: i64 = 1 << 48;
+
+///
+/// Number of polygons covering every pixel with counts of empty, exactly
+/// covered and overcovered pixels kept up to date.
+///
 #[derive(Debug, Clone)]
+struct Coverage {
+    counts: CowVec<u32>,
+    empty: u64,
+    exact: u64,
+    over: u64,
+    /// Sum of coverage above one over all pixels.
+    excess: u64,
+}
+
+impl Coverage {
+    fn new(size: usize) -> Self {
+        Coverage {
+            counts: vec![0; size].into(),
+            empty: size as u64,
+            exact: 0,
+            over: 0,
+            excess: 0,
+        }
+    }
+
+    fn add(&mut self, i: usize) {
+        let c = &mut self.counts[i];
+        match *c {
+            0 => {
+                self.empty -= 1;
+                self.exact += 1;
+            }
+            1 => {
+                self.exact -= 1;
+                self.over += 1;
+                self.excess += 1;
+            }
+            _ => self.excess += 1,
+        }
+        *c += 1;
+    }
+
+    fn remove(&mut self, i: usize) {
+        let c = &mut self.counts[i];
+        match *c {
+            1 => {
+                self.exact -= 1;
+                self.empty += 1;
+            }
+            2 => {
+                self.over -= 1;
+                self.exact += 1;
+                self.excess -= 1;
+            }
+            _ => self.excess -= 1,
+        }
+        *c -= 1;
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct DNA {
     width: i32,
     height: i32,
-    pub polygons: Vec<Polygon>,
+    pub polygons: CowVec<Polygon>,
     scale_muts: usize,
     pol_size: (i32, i32),
     pol_delta: i32,
-    pixels: Vec<u8>,
-    fitness: u64,
+    pixels: Coverage,
+    overlap_penalty: f64,
+    edges: Option<Arc<EdgeMap>>,
+    edge_weight: f64,
+    /// Sum of `EdgeMap::term` of all polygons.
+    edge_score: f64,
+    crossover: Crossover,
+    grid: Grid,
+    /// Polygons by `score_polygon`.
+    ranking: Ranking,
 }
 
 #[derive(Clone)]
 pub struct Args {
+    /// Initial polygons, topped up with random ones up to `npolygons`.
+    pub polygons: Vec<Polygon>,
     pub npolygons: i32,
     pub pol_size: (i32, i32),
     pub pol_delta: i32,
     pub scale_muts: usize,
     pub width: i32,
     pub height: i32,
+    pub crossover: Crossover,
+    /// Fitness penalty per extra polygon covering a pixel.
+    pub overlap_penalty: f64,
+    /// Edges of the reference image polygons should follow.
+    pub edges: Option<Arc<EdgeMap>>,
+    pub edge_weight: f64,
 }
 
 impl DNA {
-    fn calculate_real_fitness(&mut self) {
-        self.fitness = 0;
-
-        for p in &self.pixels {
-            let p = *p as u64;
-            self.fitness += match p {
-                0 => 1,
-                _ => 0,
-            }
+    fn calculate_fitness(&mut self) {
+        for i in 0..self.polygons.len() {
+            let p = self.polygons[i];
+            self.cover(&p, true);
+        }
+
+        if let Some(edges) = &self.edges {
+            self.edge_score = self.polygons.iter().map(|p| edges.term(p)).sum();
         }
     }
 
-    fn calculate_fitness(&mut self) {
-        for p in &self.polygons {
-            for (x, y) in p.iter_points(self.width, self.height) {
-                self.pixels[(y * self.width + x) as usize] += 1;
+    fn cover(&mut self, p: &Polygon, add: bool) {
+        for (x, y) in p.iter_points(self.width, self.height) {
+            let i = (y * self.width + x) as usize;
+            if add {
+                self.pixels.add(i);
+            } else {
+                self.pixels.remove(i);
             }
         }
-
-        self.calculate_real_fitness();
     }
 
-    fn calculate_delta_fitness(
-        &mut self,
-        mut old_polygons: Vec<Polygon>,
-        mut new_polygons: Vec<Polygon>,
-    ) {
-        while let Some(p) = old_polygons.pop() {
-            for (x, y) in p.iter_points(self.width, self.height) {
-                self.pixels[(y * self.width + x) as usize] -= 1;
+    ///
+    /// Replace polygons with given indexes. Scores of other polygons are
+    /// only adjusted on pixels whose coverage changed.
+    ///
+    fn calculate_delta_fitness(&mut self, replaced: Vec<(usize, Polygon)>) {
+        for (i, new) in replaced {
+            let old = self.polygons[i];
+            let mut changed = HashMap::new();
+            for (x, y) in old.iter_points(self.width, self.height) {
+                *changed.entry((x, y)).or_insert(0) -= 1;
+            }
+            for (x, y) in new.iter_points(self.width, self.height) {
+                *changed.entry((x, y)).or_insert(0) += 1;
             }
-        }
 
-        while let Some(p) = new_polygons.pop() {
-            for (x, y) in p.iter_points(self.width, self.height) {
-                self.pixels[(y * self.width + x) as usize] += 1;
+            self.grid.remove(i, &old);
+            self.grid.insert(i, &new);
+            self.cover(&old, false);
+            self.cover(&new, true);
+            if let Some(edges) = &self.edges {
+                self.edge_score += edges.term(&new) - edges.term(&old);
             }
-            self.polygons.push(p);
+            self.polygons[i] = new;
+
+            let mut deltas = HashMap::new();
+            for ((x, y), delta) in changed {
+                if delta == 0 {
+                    continue;
+                }
+                for &j in self.grid.candidates(x, y) {
+                    let p = &self.polygons[j];
+                    if j != i && p.contains(x, y, self.width, self.height) {
+                        *deltas.entry(j).or_insert(0) += delta;
+                    }
+                }
+            }
+            for (j, delta) in deltas {
+                let score = self.ranking.score(j) as i64 + delta * UNIT as i64;
+                self.ranking.set(j, score as u64);
+            }
+            self.ranking.set(i, self.score_polygon(&new));
         }
+    }
 
-        self.calculate_real_fitness();
+    fn rank(&mut self) {
+        self.ranking = Ranking::new(c![
+            self.score_polygon(p),
+            for p in self.polygons.iter()
+        ]);
     }
 
+    ///
+    /// Coverage of pixels under the polygon plus its weighted edge term, the
+    /// worst polygons have the biggest scores.
+    ///
     fn score_polygon(&self, polygon: &Polygon) -> u64 {
-        let mut score = 0;
-
-        for (x, y) in polygon.iter_points(self.width as i32, self.height as i32)
-        {
-            score += self.pixels[(y * self.width + x) as usize] as u64;
+        let mut coverage = 0;
+        for (x, y) in polygon.iter_points(self.width, self.height) {
+            coverage +=
+                self.pixels.counts[(y * self.width + x) as usize] as i64;
         }
-        score
+        let edge = match &self.edges {
+            Some(edges) => self.edge_weight * edges.term(polygon),
+            None => 0.,
+        };
+
+        (BIAS + coverage * UNIT as i64 + (edge * UNIT).round() as i64) as u64
     }
 }
 
 impl Selection<Args> for DNA {
     fn generate(args: Args) -> Self {
-        let polygons = c![
+        let mut polygons = args.polygons;
+        polygons.truncate(args.npolygons as usize);
+        let missing = args.npolygons as usize - polygons.len();
+        polygons.extend(c![
             Polygon::generate(
                 args.width,
                 args.height,
                 args.pol_size,
                 args.pol_delta
             ),
-            for _i in 0..args.npolygons
-        ];
+            for _i in 0..missing
+        ]);
+        let polygons = CowVec::from(polygons);
         let mut g = Self {
+            grid: Grid::new(args.width, args.height, polygons.iter()),
             polygons,
             width: args.width,
             height: args.height,
             pol_size: args.pol_size,
             pol_delta: args.pol_delta,
             scale_muts: args.scale_muts,
-            pixels: vec![0u8; args.width as usize * args.height as usize],
-            fitness: 0,
+            pixels: Coverage::new(args.width as usize * args.height as usize),
+            overlap_penalty: args.overlap_penalty,
+            edges: args.edges,
+            edge_weight: args.edge_weight,
+            edge_score: 0.,
+            crossover: args.crossover,
+            ranking: Ranking::new(Vec::new()),
         };
         g.calculate_fitness();
+        g.rank();
         g
     }
 
     fn mutate(&self) -> Self {
         let mut dna = self.clone();
-        dna.polygons.sort_by_key(|p| self.score_polygon(&p));
-
-        let mut old_polygons = Vec::new();
-        let new_polygons = c![
-            Polygon::generate(
+        let replaced = c![
+            (i, Polygon::generate(
                 self.width,
                 self.height,
                 self.pol_size,
                 self.pol_delta
-            ),
-            for _i in 0..self.scale_muts
+            )),
+            for i in self.ranking.worst(self.scale_muts)
         ];
 
-        for _ in 0..self.scale_muts {
-            old_polygons.push(dna.polygons.pop().unwrap());
-        }
+        dna.calculate_delta_fitness(replaced);
 
-        dna.calculate_delta_fitness(old_polygons, new_polygons);
+        dna
+    }
 
+    fn crossover(&self, other: &Self) -> Self {
+        let polygons = CowVec::from(self.crossover.apply(
+            &self.polygons.to_vec(),
+            &other.polygons.to_vec(),
+            self.width,
+            self.height,
+        ));
+        let mut dna = Self {
+            grid: Grid::new(self.width, self.height, polygons.iter()),
+            polygons,
+            pixels: Coverage::new(self.pixels.counts.len()),
+            ..self.clone()
+        };
+        dna.calculate_fitness();
+        dna.rank();
         dna
     }
 
-    fn fitness(&self) -> u64 {
-        self.fitness
+    fn scale(&self) -> usize {
+        self.scale_muts
     }
 
-    fn print(&self, ngen: u64, fitness: u64) {
-        let mut exact = 0;
-        let mut empty = 0;
-        let mut rest = 0;
+    fn set_scale(&mut self, scale: usize) {
+        self.scale_muts = scale.min(self.polygons.len());
+    }
 
-        for p in &self.pixels {
-            match *p as u64 {
-                1 => exact += 1,
-                0 => empty += 1,
-                _ => rest += 1,
-            }
-        }
+    ///
+    /// Share of polygons with different vertices.
+    ///
+    fn distance(&self, other: &Self) -> f64 {
+        let differ = self
+            .polygons
+            .iter()
+            .zip(other.polygons.iter())
+            .filter(|(a, b)| a.points != b.points)
+            .count();
+        let n = self.polygons.len().max(other.polygons.len());
+        (differ + n - self.polygons.len().min(other.polygons.len())) as f64
+            / n.max(1) as f64
+    }
+
+    ///
+    /// Save coverage map: white pixels are covered, black are not.
+    ///
+    fn save(&self, path: &Path) {
+        let img = image::GrayImage::from_fn(
+            self.width as u32,
+            self.height as u32,
+            |x, y| {
+                let covered =
+                    self.pixels.counts[(y * self.width as u32 + x) as usize];
+                image::Luma([if covered > 0 { 255 } else { 0 }])
+            },
+        );
+        img.save_with_format(path, image::ImageFormat::Png).unwrap();
+    }
+
+    ///
+    /// Only chunks not shared with other individuals are counted.
+    ///
+    fn size_hint(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.polygons.unshared() * std::mem::size_of::<Polygon>()
+            + self.pixels.counts.unshared() * std::mem::size_of::<u32>()
+            + self.grid.size_hint()
+            + self.ranking.size_hint()
+    }
+
+    fn full_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.polygons.len() * std::mem::size_of::<Polygon>()
+            + self.pixels.counts.len() * std::mem::size_of::<u32>()
+            + self.grid.size()
+            + self.ranking.size()
+    }
+
+    ///
+    /// Number of empty pixels plus penalties for overlap and for polygons
+    /// not following image edges if they are set.
+    ///
+    fn fitness(&self) -> f64 {
+        self.pixels.empty as f64
+            + self.overlap_penalty * self.pixels.excess as f64
+            + self.edge_weight * self.edge_score
+    }
+
+    fn print(&self, ngen: u64, fitness: f64) {
         println!(
             "polygonize: Showing generation {} fitness: {} exact {} empty {} rest {}",
-            ngen, fitness, exact, empty, rest
+            ngen, fitness, self.pixels.exact, self.pixels.empty, self.pixels.over
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edges::Detector;
+    use crate::fixtures::{step, triangle, BLACK};
+
+    fn edges() -> Arc<EdgeMap> {
+        Arc::new(EdgeMap::new(&step(40, 30), Detector::Sobel, (50., 100.)))
+    }
+
+    fn args(edges: Option<Arc<EdgeMap>>) -> Args {
+        Args {
+            polygons: Vec::new(),
+            npolygons: 60,
+            pol_size: (12, 12),
+            pol_delta: 4,
+            scale_muts: 5,
+            width: 40,
+            height: 30,
+            crossover: Crossover::Uniform,
+            overlap_penalty: 0.,
+            edges,
+            edge_weight: 0.5,
+        }
+    }
+
+    #[test]
+    fn delta_keeps_ranking_scores() {
+        for edges in &[None, Some(edges())] {
+            let mut dna = DNA::generate(args(edges.clone()));
+            for _i in 0..20 {
+                dna = dna.mutate();
+            }
+
+            for (i, p) in dna.polygons.iter().enumerate() {
+                assert_eq!(dna.ranking.score(i), dna.score_polygon(p));
+            }
+        }
+    }
+
+    #[test]
+    fn edge_term_ranks_straddling_polygons_worse() {
+        // Equal triangles, one with a side along the edge and one across
+        // it. The latter goes first, so a tie would rank the former worse.
+        let across = triangle([(17, 16), (17, 28), (23, 22)], BLACK);
+        let along = triangle([(20, 1), (20, 13), (26, 7)], BLACK);
+        let dna = DNA::generate(Args {
+            polygons: vec![across, along],
+            npolygons: 2,
+            ..args(Some(edges()))
+        });
+
+        assert_eq!(dna.ranking.worst(1), vec![0]);
+    }
+}
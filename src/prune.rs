@@ -0,0 +1,256 @@
+use super::draw::*;
+use super::grid::Grid;
+use super::render::{distance, Canvas};
+use rayon::iter::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+
+/// What pruning did.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub before: usize,
+    pub after: usize,
+    /// Colorize fitness before and after pruning.
+    pub error_before: f64,
+    pub error_after: f64,
+}
+
+impl Report {
+    /// Report of all tiles pruned one by one.
+    pub fn sum(reports: &[Report]) -> Self {
+        Report {
+            before: reports.iter().map(|r| r.before).sum(),
+            after: reports.iter().map(|r| r.after).sum(),
+            error_before: reports.iter().map(|r| r.error_before).sum(),
+            error_after: reports.iter().map(|r| r.error_after).sum(),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = if self.error_before == 0. {
+            0.
+        } else {
+            (self.error_after / self.error_before - 1.) * 100.
+        };
+
+        write!(
+            f,
+            "prune: removed {} of {} polygons ({} left), error {:.0} -> {:.0} ({:+.3}%)",
+            self.before - self.after,
+            self.before,
+            self.after,
+            self.error_before,
+            self.error_after,
+            change,
+        )
+    }
+}
+
+fn error(canvas: &Canvas, ref_img: &image::RgbImage) -> f64 {
+    ref_img
+        .pixels()
+        .enumerate()
+        .map(|(i, px)| distance(canvas.color(i), *px))
+        .sum()
+}
+
+///
+/// How much the error grows if `p` is removed. Negative for polygons that
+/// make the image worse, zero for ones not visible at all.
+///
+fn contribution(
+    canvas: &Canvas,
+    ref_img: &image::RgbImage,
+    p: &Polygon,
+) -> f64 {
+    let mut delta = 0.;
+
+    for (x, y) in p.iter_points(canvas.width as i32, canvas.height as i32) {
+        let i = (y as u32 * canvas.width + x as u32) as usize;
+        let px = *ref_img.get_pixel(x as u32, y as u32);
+
+        delta += distance(canvas.color_without(i, p.color_at(x, y)), px)
+            - distance(canvas.color(i), px);
+    }
+    delta
+}
+
+/// Heap entry ordered so that the least contributing polygon pops first.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    score: f64,
+    index: usize,
+    stamp: usize,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .total_cmp(&self.score)
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+///
+/// Remove polygons contributing no more than `threshold` to the image and/or
+/// the least contributing ones until only `target` are left. The least
+/// contributing polygon is removed first, and after every removal
+/// contributions of polygons covering its pixels are recalculated.
+///
+pub fn prune(
+    polygons: Vec<Polygon>,
+    ref_img: &image::RgbImage,
+    bg: Color,
+    threshold: Option<f64>,
+    target: Option<usize>,
+) -> (Vec<Polygon>, Report) {
+    let (width, height) = ref_img.dimensions();
+    let mut canvas = Canvas::new(width, height, bg);
+    for p in &polygons {
+        canvas.add(p);
+    }
+    let error_before = error(&canvas, ref_img);
+
+    let mut grid = Grid::new(width as i32, height as i32, polygons.iter());
+    let mut stamps = vec![0; polygons.len()];
+    let mut heap = polygons
+        .par_iter()
+        .enumerate()
+        .map(|(index, p)| Entry {
+            score: contribution(&canvas, ref_img, p),
+            index,
+            stamp: 0,
+        })
+        .collect::<BinaryHeap<_>>();
+
+    let mut removed = vec![false; polygons.len()];
+    let mut left = polygons.len();
+
+    while let Some(entry) = heap.pop() {
+        if removed[entry.index] || stamps[entry.index] != entry.stamp {
+            continue;
+        }
+
+        let over_target = target.is_some_and(|t| left > t);
+        let useless = threshold.is_some_and(|t| entry.score <= t);
+        if !over_target && !useless {
+            break;
+        }
+
+        let p = &polygons[entry.index];
+        canvas.remove(p);
+        grid.remove(entry.index, p);
+        removed[entry.index] = true;
+        left -= 1;
+
+        let mut near = Vec::new();
+        for (x, y) in p.iter_points(width as i32, height as i32) {
+            near.extend(grid.candidates(x, y).iter().filter(|i| {
+                polygons[**i].contains(x, y, width as i32, height as i32)
+            }));
+        }
+        near.sort_unstable();
+        near.dedup();
+        let scores = near
+            .par_iter()
+            .map(|i| contribution(&canvas, ref_img, &polygons[*i]))
+            .collect::<Vec<_>>();
+        for (i, score) in near.into_iter().zip(scores) {
+            stamps[i] += 1;
+            heap.push(Entry {
+                score,
+                index: i,
+                stamp: stamps[i],
+            });
+        }
+
+        // Drop stale entries once they outnumber live ones.
+        if heap.len() > 2 * left {
+            heap.retain(|e| !removed[e.index] && stamps[e.index] == e.stamp);
+        }
+    }
+
+    let report = Report {
+        before: polygons.len(),
+        after: left,
+        error_before,
+        error_after: error(&canvas, ref_img),
+    };
+    let polygons = polygons
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(p, _)| p)
+        .collect();
+
+    (polygons, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::BLACK;
+
+    const RED: Color = Color(image::Rgb([255, 0, 0]));
+
+    /// Triangle in the top left corner of a 16x16 image shifted by `x`.
+    fn triangle(x: i32, color: Color) -> Polygon {
+        crate::fixtures::triangle([(x, 0), (x + 15, 0), (x, 15)], color)
+    }
+
+    fn red_image() -> image::RgbImage {
+        image::RgbImage::from_pixel(16, 16, RED.0)
+    }
+
+    #[test]
+    fn threshold_rescores_after_removal() {
+        let polygons = vec![triangle(0, RED), triangle(0, RED)];
+
+        let (left, report) =
+            prune(polygons, &red_image(), BLACK, Some(0.), None);
+        assert_eq!(left.len(), 1);
+        assert_eq!(report.error_after, report.error_before);
+        assert!(report.to_string().ends_with("(+0.000%)"));
+    }
+
+    #[test]
+    fn threshold_removes_harmful_and_hidden() {
+        let polygons =
+            vec![triangle(0, RED), triangle(0, BLACK), triangle(100, RED)];
+
+        let (left, report) =
+            prune(polygons, &red_image(), BLACK, Some(0.), None);
+        assert_eq!(left, vec![triangle(0, RED)]);
+        assert!(report.error_after < report.error_before);
+    }
+
+    #[test]
+    fn target_keeps_most_useful() {
+        let polygons =
+            vec![triangle(100, RED), triangle(0, RED), triangle(0, BLACK)];
+
+        let (left, report) =
+            prune(polygons, &red_image(), BLACK, None, Some(1));
+        assert_eq!(left, vec![triangle(0, RED)]);
+        assert_eq!(report.before, 3);
+        assert_eq!(report.after, 1);
+    }
+}
@@ -0,0 +1,52 @@
+use super::selection::Stop;
+use image::imageops::{self, FilterType};
+
+/// One level of the coarse-to-fine schedule.
+#[derive(Debug, Clone, Copy)]
+pub struct Level {
+    /// Reference image is downscaled by this factor.
+    pub scale: u32,
+    pub stop: Stop,
+}
+
+///
+/// Build the schedule from per-level settings. Lists shorter than `scales`
+/// are padded with their last value.
+///
+pub fn levels(
+    scales: &[u32],
+    retries: &[i32],
+    max_gens: &[u64],
+    epsilon: f64,
+) -> Vec<Level> {
+    fn nth<T: Copy>(v: &[T], i: usize) -> Option<T> {
+        v.get(i).or_else(|| v.last()).copied()
+    }
+
+    scales
+        .iter()
+        .enumerate()
+        .map(|(i, &scale)| Level {
+            scale: scale.max(1),
+            stop: Stop {
+                retries: nth(retries, i).unwrap_or(2),
+                max_gens: nth(max_gens, i),
+                epsilon,
+            },
+        })
+        .collect()
+}
+
+pub fn downscale(img: &image::RgbImage, scale: u32) -> image::RgbImage {
+    if scale <= 1 {
+        return img.clone();
+    }
+
+    let (width, height) = img.dimensions();
+    imageops::resize(
+        img,
+        (width / scale).max(1),
+        (height / scale).max(1),
+        FilterType::Triangle,
+    )
+}
@@ -0,0 +1,230 @@
+use super::cow::CowVec;
+use std::sync::Arc;
+
+type Key = (u64, usize);
+type Tree = Option<Arc<Node>>;
+
+///
+/// Node of a persistent treap: changing it copies only shared nodes on the
+/// path from the root, everything else stays shared with other copies.
+///
+#[derive(Debug, Clone)]
+struct Node {
+    key: Key,
+    priority: u64,
+    left: Tree,
+    right: Tree,
+}
+
+/// Pseudo random heap priority of a key, so equal rankings get equal trees.
+fn priority((score, i): Key) -> u64 {
+    let mut z = score ^ (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Split `tree` into keys less than `key` and the rest.
+fn split(tree: Tree, key: Key) -> (Tree, Tree) {
+    let mut tree = match tree {
+        Some(tree) => tree,
+        None => return (None, None),
+    };
+    // Nodes shared with other trees are copied, own ones changed in place.
+    let node = Arc::make_mut(&mut tree);
+
+    if node.key < key {
+        let (left, right) = split(node.right.take(), key);
+        node.right = left;
+        (Some(tree), right)
+    } else {
+        let (left, right) = split(node.left.take(), key);
+        node.left = right;
+        (left, Some(tree))
+    }
+}
+
+/// Join trees with all keys of `a` less than all keys of `b`.
+fn merge(a: Tree, b: Tree) -> Tree {
+    match (a, b) {
+        (None, tree) | (tree, None) => tree,
+        (Some(mut a), Some(b)) if a.priority > b.priority => {
+            let node = Arc::make_mut(&mut a);
+            node.right = merge(node.right.take(), Some(b));
+            Some(a)
+        }
+        (Some(a), Some(mut b)) => {
+            let node = Arc::make_mut(&mut b);
+            node.left = merge(Some(a), node.left.take());
+            Some(b)
+        }
+    }
+}
+
+/// Number of nodes not shared with other trees.
+fn unshared(tree: &Tree) -> usize {
+    match tree {
+        Some(node) if Arc::strong_count(node) == 1 => {
+            1 + unshared(&node.left) + unshared(&node.right)
+        }
+        _ => 0,
+    }
+}
+
+fn insert(tree: Tree, key: Key) -> Tree {
+    let (left, right) = split(tree, key);
+    let node = Node {
+        key,
+        priority: priority(key),
+        left: None,
+        right: None,
+    };
+    merge(merge(left, Some(Arc::new(node))), right)
+}
+
+fn remove(tree: Tree, key: Key) -> Tree {
+    let (left, rest) = split(tree, key);
+    let (_, right) = split(rest, (key.0, key.1 + 1));
+    merge(left, right)
+}
+
+///
+/// Scores of polygons by index, ordered so that the worst (biggest) ones can
+/// be taken without sorting everything. Copies share everything, changing a
+/// score copies about a logarithm of the number of polygons tree nodes and
+/// one chunk of scores, so the cost of a mutant doesn't depend on how many
+/// polygons it has.
+///
+#[derive(Debug, Clone)]
+pub struct Ranking {
+    sorted: Tree,
+    scores: CowVec<u64>,
+}
+
+impl Ranking {
+    pub fn new(scores: Vec<u64>) -> Self {
+        let mut sorted = None;
+        for (i, score) in scores.iter().enumerate() {
+            sorted = insert(sorted, (*score, i));
+        }
+
+        Ranking {
+            sorted,
+            scores: scores.into(),
+        }
+    }
+
+    pub fn score(&self, i: usize) -> u64 {
+        self.scores[i]
+    }
+
+    pub fn set(&mut self, i: usize, score: u64) {
+        let old = self.scores[i];
+        if old == score {
+            return;
+        }
+
+        let sorted = remove(self.sorted.take(), (old, i));
+        self.sorted = insert(sorted, (score, i));
+        self.scores[i] = score;
+    }
+
+    /// Memory taken by nodes and scores owned only by this copy in bytes.
+    pub fn size_hint(&self) -> usize {
+        unshared(&self.sorted) * std::mem::size_of::<Node>()
+            + self.scores.unshared() * std::mem::size_of::<u64>()
+    }
+
+    /// Memory taken by all nodes and scores in bytes.
+    pub fn size(&self) -> usize {
+        self.scores.len()
+            * (std::mem::size_of::<Node>() + std::mem::size_of::<u64>())
+    }
+
+    ///
+    /// Indexes of at most `n` polygons with the biggest scores, the worst
+    /// first.
+    ///
+    pub fn worst(&self, n: usize) -> Vec<usize> {
+        let mut worst = Vec::with_capacity(n);
+        let mut stack = Vec::new();
+        let mut node = self.sorted.as_ref();
+
+        while worst.len() < n {
+            while let Some(next) = node {
+                stack.push(next);
+                node = next.right.as_ref();
+            }
+            match stack.pop() {
+                Some(next) => {
+                    worst.push(next.key.1);
+                    node = next.left.as_ref();
+                }
+                None => break,
+            }
+        }
+        worst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::randrange;
+
+    fn all_nodes(tree: &Tree, nodes: &mut Vec<*const Node>) {
+        if let Some(node) = tree {
+            nodes.push(Arc::as_ptr(node));
+            all_nodes(&node.left, nodes);
+            all_nodes(&node.right, nodes);
+        }
+    }
+
+    /// Nodes of `a` which are not nodes of `b`.
+    fn copied(a: &Tree, b: &Tree) -> usize {
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        all_nodes(a, &mut ours);
+        all_nodes(b, &mut theirs);
+        theirs.sort_unstable();
+        ours.iter()
+            .filter(|p| theirs.binary_search(p).is_err())
+            .count()
+    }
+
+    #[test]
+    fn worst_is_sorted() {
+        let scores = c![randrange(0, 1000u64), for _i in 0..500];
+        let mut ranking = Ranking::new(scores.clone());
+        let mut scores = scores;
+        for _i in 0..200 {
+            let (i, score) = (randrange(0, 500), randrange(0, 1000u64));
+            ranking.set(i, score);
+            scores[i] = score;
+        }
+
+        let worst = ranking.worst(50);
+        assert_eq!(worst.len(), 50);
+        let mut expected = scores.clone();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(c![scores[i], for i in worst], expected[..50].to_vec());
+        assert_eq!(ranking.worst(1000).len(), 500);
+        assert_eq!(Ranking::new(Vec::new()).worst(3), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn mutant_cost_does_not_grow_with_polygons() {
+        for n in &[1 << 10, 1 << 14, 1 << 17] {
+            let parent =
+                Ranking::new(c![randrange(0, 1 << 20), for _i in 0..*n]);
+            let mut child = parent.clone();
+            for _i in 0..10 {
+                child.set(randrange(0, *n), randrange(0, 1 << 20));
+            }
+
+            // Ten changes copy a few tree paths and at most ten chunks of
+            // scores, whatever the number of polygons.
+            assert!(copied(&child.sorted, &parent.sorted) <= 10 * 64);
+            assert!(child.scores.unshared() <= 10 * 4096);
+        }
+    }
+}
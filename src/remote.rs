@@ -0,0 +1,676 @@
+use super::cow::CowVec;
+use super::draw::*;
+use super::render::{self, Kernel};
+use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Number of genomes a worker keeps to evaluate diffs against.
+const CACHE: usize = 256;
+/// Genome homes remembered by coordinator before they are forgotten.
+const HOMES: usize = 1 << 16;
+/// Biggest reference image a worker accepts, in pixels.
+const MAX_PIXELS: u64 = 1 << 27;
+/// Biggest genome a worker accepts.
+const MAX_POLYGONS: usize = 1 << 24;
+/// Polygons read before they are known to arrive are allocated at most in
+/// chunks this big.
+const PREALLOC: usize = 4096;
+
+// Requests
+const INIT: u8 = 0;
+const FULL: u8 = 1;
+const EVAL: u8 = 2;
+// Replies
+const FITNESS: u8 = 0;
+const MISSING: u8 = 1;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn put_u8(w: &mut impl Write, v: u8) -> io::Result<()> {
+    w.write_all(&[v])
+}
+
+fn put_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn put_u64(w: &mut impl Write, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn put_color(w: &mut impl Write, c: Color) -> io::Result<()> {
+    w.write_all(&(c.0).0)
+}
+
+fn put_polygon(w: &mut impl Write, p: &Polygon) -> io::Result<()> {
+    for point in &p.points {
+        put_u32(w, point.x as u32)?;
+        put_u32(w, point.y as u32)?;
+    }
+    put_color(w, p.color)?;
+    match p.vertex_colors {
+        Some(colors) => {
+            put_u8(w, 1)?;
+            for c in &colors {
+                put_color(w, *c)?;
+            }
+            Ok(())
+        }
+        None => put_u8(w, 0),
+    }
+}
+
+fn get_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn get_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn get_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn get_color(r: &mut impl Read) -> io::Result<Color> {
+    let mut buf = [0; 3];
+    r.read_exact(&mut buf)?;
+    Ok(Color(image::Rgb(buf)))
+}
+
+fn get_polygon(r: &mut impl Read) -> io::Result<Polygon> {
+    let mut points = [Point { x: 0, y: 0 }; 3];
+    for point in &mut points {
+        point.x = get_u32(r)? as i32;
+        point.y = get_u32(r)? as i32;
+    }
+    let color = get_color(r)?;
+    let vertex_colors = match get_u8(r)? {
+        0 => None,
+        1 => Some([get_color(r)?, get_color(r)?, get_color(r)?]),
+        _ => return Err(invalid("bad polygon")),
+    };
+
+    Ok(Polygon {
+        color,
+        points,
+        vertex_colors,
+    })
+}
+
+fn kernel_code(kernel: Kernel) -> u8 {
+    match kernel {
+        Kernel::Scalar => 0,
+        Kernel::Simd => 1,
+        Kernel::Check => 2,
+    }
+}
+
+fn kernel_from_code(code: u8) -> io::Result<Kernel> {
+    match code {
+        0 => Ok(Kernel::Scalar),
+        1 => Ok(Kernel::Simd),
+        2 => Ok(Kernel::Check),
+        _ => Err(invalid("bad kernel")),
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+/// Individual to evaluate, as a change of a genome evaluated before.
+pub struct Job<'a> {
+    pub id: u64,
+    pub parent: u64,
+    pub parent_polygons: &'a CowVec<Polygon>,
+    pub polygons: &'a CowVec<Polygon>,
+}
+
+///
+/// Coordinator side: connections to workers evaluating colorize fitness
+/// against one reference image. Every worker keeps genomes it evaluated,
+/// children are sent to the worker holding their parent as a list of
+/// changed polygons.
+///
+#[derive(Debug)]
+pub struct Pool {
+    /// Connections, `None` once they failed.
+    workers: Vec<Mutex<Option<Connection>>>,
+    /// Worker holding genome with given id.
+    homes: Mutex<HashMap<u64, usize>>,
+    next: AtomicUsize,
+}
+
+impl Connection {
+    fn send_full(&mut self, job: &Job) -> io::Result<()> {
+        let w = &mut self.writer;
+
+        put_u8(w, FULL)?;
+        put_u64(w, job.id)?;
+        put_u32(w, job.polygons.len() as u32)?;
+        for p in job.polygons.iter() {
+            put_polygon(w, p)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, job: &Job, held: bool) -> io::Result<()> {
+        let diff = job.polygons.diff(job.parent_polygons);
+        if !held || diff.len() > job.polygons.len() / 2 {
+            return self.send_full(job);
+        }
+        let w = &mut self.writer;
+
+        put_u8(w, EVAL)?;
+        put_u64(w, job.id)?;
+        put_u64(w, job.parent)?;
+        put_u32(w, job.polygons.len() as u32)?;
+        put_u32(w, diff.len() as u32)?;
+        for i in diff {
+            put_u32(w, i as u32)?;
+            put_polygon(w, &job.polygons[i])?;
+        }
+        Ok(())
+    }
+
+    /// Fitness or `None` if worker doesn't have the parent any more.
+    fn receive(&mut self) -> io::Result<Option<f64>> {
+        match get_u8(&mut self.reader)? {
+            FITNESS => Ok(Some(f64::from_bits(get_u64(&mut self.reader)?))),
+            MISSING => Ok(None),
+            _ => Err(invalid("bad reply")),
+        }
+    }
+
+    ///
+    /// Pipeline all jobs, then resend in full ones whose parents were
+    /// evicted.
+    ///
+    fn evaluate(&mut self, jobs: &[(&Job, bool)]) -> io::Result<Vec<f64>> {
+        for (job, held) in jobs {
+            self.send(job, *held)?;
+        }
+        self.writer.flush()?;
+
+        let mut fitness = vec![0.; jobs.len()];
+        let mut missing = Vec::new();
+        for (i, f) in fitness.iter_mut().enumerate() {
+            match self.receive()? {
+                Some(v) => *f = v,
+                None => missing.push(i),
+            }
+        }
+
+        for &i in &missing {
+            self.send_full(jobs[i].0)?;
+        }
+        self.writer.flush()?;
+        for i in missing {
+            fitness[i] = self.receive()?.ok_or_else(|| invalid("missing"))?;
+        }
+        Ok(fitness)
+    }
+}
+
+impl Pool {
+    ///
+    /// Connect to workers and send them reference image along with settings
+    /// of fitness.
+    ///
+    pub fn connect(
+        addrs: &[String],
+        ref_img: &image::RgbImage,
+        bg: Color,
+        kernel: Kernel,
+    ) -> io::Result<Self> {
+        let mut workers = Vec::new();
+
+        for addr in addrs {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            let mut conn = Connection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: BufWriter::new(stream),
+            };
+            let w = &mut conn.writer;
+
+            put_u8(w, INIT)?;
+            put_u32(w, ref_img.width())?;
+            put_u32(w, ref_img.height())?;
+            put_color(w, bg)?;
+            put_u8(w, kernel_code(kernel))?;
+            w.write_all(ref_img)?;
+            w.flush()?;
+            workers.push(Mutex::new(Some(conn)));
+        }
+
+        Ok(Pool {
+            workers,
+            homes: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether some worker is still connected.
+    pub fn alive(&self) -> bool {
+        self.workers.iter().any(|w| w.lock().unwrap().is_some())
+    }
+
+    ///
+    /// Fitness of every job, computed by workers in parallel, or `None` for
+    /// jobs no worker could evaluate. A worker failing is disconnected and
+    /// its jobs are handed to the others, which keep their results.
+    ///
+    pub fn evaluate(&self, jobs: &[Job]) -> Vec<Option<f64>> {
+        let mut fitness = vec![None; jobs.len()];
+        let mut pending = (0..jobs.len()).collect::<Vec<_>>();
+
+        while !pending.is_empty() {
+            let alive = c![
+                w,
+                for w in 0..self.workers.len(),
+                if self.workers[w].lock().unwrap().is_some()
+            ];
+            if alive.is_empty() {
+                break;
+            }
+
+            let mut assigned = vec![Vec::new(); self.workers.len()];
+            {
+                let homes = self.homes.lock().unwrap();
+                for &i in &pending {
+                    let (w, held) = match homes.get(&jobs[i].parent) {
+                        Some(w) if alive.contains(w) => (*w, true),
+                        _ => {
+                            let next =
+                                self.next.fetch_add(1, Ordering::Relaxed);
+                            (alive[next % alive.len()], false)
+                        }
+                    };
+                    assigned[w].push((i, held));
+                }
+            }
+
+            let results = assigned
+                .par_iter()
+                .enumerate()
+                .map(|(w, js)| {
+                    if js.is_empty() {
+                        return Ok(Vec::new());
+                    }
+                    let js = c![(&jobs[j.0], j.1), for j in js];
+                    let mut conn = self.workers[w].lock().unwrap();
+                    let result = match conn.as_mut() {
+                        Some(conn) => conn.evaluate(&js),
+                        None => Err(invalid("disconnected")),
+                    };
+                    if let Err(e) = &result {
+                        // The stream may be left in the middle of a reply.
+                        eprintln!("remote: worker {}: {}, dropping it", w, e);
+                        *conn = None;
+                    }
+                    result
+                })
+                .collect::<Vec<_>>();
+
+            pending.clear();
+            let mut homes = self.homes.lock().unwrap();
+            if homes.len() > HOMES {
+                homes.clear();
+            }
+            for (w, (js, results)) in assigned.iter().zip(results).enumerate() {
+                match results {
+                    Ok(results) => {
+                        for ((i, _), f) in js.iter().zip(results) {
+                            fitness[*i] = Some(f);
+                            homes.insert(jobs[*i].id, w);
+                        }
+                    }
+                    Err(_) => pending.extend(js.iter().map(|(i, _)| *i)),
+                }
+            }
+        }
+        fitness
+    }
+}
+
+///
+/// Worker side state of one coordinator connection.
+///
+struct Session {
+    ref_img: image::RgbImage,
+    bg: Color,
+    kernel: Kernel,
+    cache: HashMap<u64, CowVec<Polygon>>,
+    order: VecDeque<u64>,
+}
+
+impl Session {
+    fn init(r: &mut impl Read) -> io::Result<Self> {
+        if get_u8(r)? != INIT {
+            return Err(invalid("expected init"));
+        }
+        let (width, height) = (get_u32(r)?, get_u32(r)?);
+        if width as u64 * height as u64 > MAX_PIXELS {
+            return Err(invalid("image too big"));
+        }
+        let bg = get_color(r)?;
+        let kernel = kernel_from_code(get_u8(r)?)?;
+        let mut raw = vec![0; width as usize * height as usize * 3];
+        r.read_exact(&mut raw)?;
+
+        Ok(Session {
+            ref_img: image::RgbImage::from_raw(width, height, raw)
+                .ok_or_else(|| invalid("bad image"))?,
+            bg,
+            kernel,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+        })
+    }
+
+    fn fitness(&self, polygons: &CowVec<Polygon>) -> f64 {
+        let (width, height) = self.ref_img.dimensions();
+        let img = render::draw(width, height, self.bg, polygons.iter());
+        render::error(&img, &self.ref_img, self.kernel)
+    }
+
+    fn keep(&mut self, id: u64, polygons: CowVec<Polygon>) {
+        self.cache.insert(id, polygons);
+        self.order.push_back(id);
+        if self.order.len() > CACHE {
+            let old = self.order.pop_front().unwrap();
+            self.cache.remove(&old);
+        }
+    }
+
+    ///
+    /// Read one request, `None` if it refers to an unknown parent.
+    ///
+    fn request(
+        &self,
+        r: &mut impl Read,
+        tag: u8,
+    ) -> io::Result<(u64, Option<CowVec<Polygon>>)> {
+        match tag {
+            FULL => {
+                let id = get_u64(r)?;
+                let n = get_u32(r)? as usize;
+                if n > MAX_POLYGONS {
+                    return Err(invalid("too many polygons"));
+                }
+                let mut polygons = Vec::with_capacity(n.min(PREALLOC));
+                for _ in 0..n {
+                    polygons.push(get_polygon(r)?);
+                }
+                Ok((id, Some(polygons.into())))
+            }
+            EVAL => {
+                let (id, parent) = (get_u64(r)?, get_u64(r)?);
+                let len = get_u32(r)? as usize;
+                let n = get_u32(r)? as usize;
+                if len > MAX_POLYGONS || n > len {
+                    return Err(invalid("too many polygons"));
+                }
+                let mut changes = Vec::with_capacity(n.min(PREALLOC));
+                for _ in 0..n {
+                    let i = get_u32(r)? as usize;
+                    if i >= len {
+                        return Err(invalid("bad polygon index"));
+                    }
+                    changes.push((i, get_polygon(r)?));
+                }
+
+                let mut polygons = match self.cache.get(&parent) {
+                    Some(p) => p.clone(),
+                    None => return Ok((id, None)),
+                };
+                while polygons.len() > len {
+                    polygons.pop();
+                }
+                for (i, p) in changes {
+                    if i < polygons.len() {
+                        polygons[i] = p;
+                    } else if i == polygons.len() {
+                        polygons.push(p);
+                    } else {
+                        return Err(invalid("bad polygon index"));
+                    }
+                }
+                if polygons.len() != len {
+                    return Err(invalid("bad diff"));
+                }
+                Ok((id, Some(polygons)))
+            }
+            _ => Err(invalid("bad request")),
+        }
+    }
+}
+
+fn session(stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut session = Session::init(&mut reader)?;
+
+    loop {
+        let tag = match get_u8(&mut reader) {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        match session.request(&mut reader, tag)? {
+            (id, Some(polygons)) => {
+                put_u8(&mut writer, FITNESS)?;
+                put_u64(&mut writer, session.fitness(&polygons).to_bits())?;
+                session.keep(id, polygons);
+            }
+            (_, None) => put_u8(&mut writer, MISSING)?,
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+///
+/// Run worker evaluating fitness for coordinators connecting to `listener`.
+///
+pub fn serve(listener: TcpListener) -> io::Result<()> {
+    eprintln!("worker: listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        std::thread::spawn(move || match session(stream) {
+            Ok(()) => eprintln!("worker: {} disconnected", peer),
+            Err(e) => eprintln!("worker: {}: {}", peer, e),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colorize;
+    use crate::fixtures::{triangle, BLACK};
+    use crate::selection::Selection;
+
+    fn worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || serve(listener));
+        addr
+    }
+
+    /// Worker which accepts the reference image and hangs up.
+    fn dead_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = Session::init(&mut BufReader::new(stream.unwrap()));
+            }
+        });
+        addr
+    }
+
+    fn image() -> image::RgbImage {
+        image::RgbImage::from_fn(40, 30, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 8) as u8, ((x ^ y) * 4) as u8])
+        })
+    }
+
+    fn colors(img: &image::RgbImage) -> colorize::Colors {
+        colorize::Colors::new(img, Some(12))
+    }
+
+    fn dna(img: &image::RgbImage) -> colorize::DNA<'_> {
+        colorize::DNA::generate(colorize::Args {
+            ref_img: img,
+            scale_muts: 4,
+            polygons: c![Polygon::generate(40, 30, (10, 10), 4), for _i in 0..50],
+            colors: colors(img),
+            gouraud: false,
+            color_step: 20.,
+            step_decay: 1.,
+            jump_ratio: 0.5,
+            crossover: Crossover::Uniform,
+            kernel: Kernel::Simd,
+            snapshots: "./img/".to_string(),
+        })
+    }
+
+    fn job<'a>(
+        id: u64,
+        parent: u64,
+        dna: &'a colorize::DNA,
+        of: &'a colorize::DNA,
+    ) -> Job<'a> {
+        Job {
+            id,
+            parent,
+            parent_polygons: &of.polygons,
+            polygons: &dna.polygons,
+        }
+    }
+
+    /// Fitness of `kids` computed locally from scratch.
+    fn local(kids: &[colorize::DNA]) -> Vec<Option<f64>> {
+        c![
+            {
+                let mut kid = kid.clone();
+                kid.evaluate();
+                Some(kid.fitness())
+            },
+            for kid in kids
+        ]
+    }
+
+    #[test]
+    fn remote_fitness_equals_local() {
+        let img = image();
+        let parent = dna(&img);
+        let kids = c![parent.mutate_unscored(), for _i in 0..8];
+
+        let pool = Pool::connect(
+            &[worker(), worker()],
+            &img,
+            colors(&img).get_bg(),
+            Kernel::Simd,
+        )
+        .unwrap();
+        let fitness = pool.evaluate(&[job(1, 0, &parent, &parent)]);
+        assert_eq!(fitness, local(std::slice::from_ref(&parent)));
+
+        let jobs =
+            c![job(2 + i as u64, 1, &kids[i], &parent), for i in 0..kids.len()];
+        assert_eq!(pool.evaluate(&jobs), local(&kids));
+    }
+
+    #[test]
+    fn jobs_of_dead_worker_go_to_others() {
+        let img = image();
+        let parent = dna(&img);
+        let kids = c![parent.mutate_unscored(), for _i in 0..8];
+
+        let pool = Pool::connect(
+            &[dead_worker(), worker()],
+            &img,
+            colors(&img).get_bg(),
+            Kernel::Simd,
+        )
+        .unwrap();
+        let jobs =
+            c![job(1 + i as u64, 0, &kids[i], &parent), for i in 0..kids.len()];
+        assert_eq!(pool.evaluate(&jobs), local(&kids));
+        assert!(pool.workers[0].lock().unwrap().is_none());
+        assert!(pool.alive());
+
+        let pool = Pool::connect(
+            &[dead_worker()],
+            &img,
+            colors(&img).get_bg(),
+            Kernel::Simd,
+        )
+        .unwrap();
+        assert_eq!(pool.evaluate(&jobs), vec![None; kids.len()]);
+        assert!(!pool.alive());
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected() {
+        let mut init = vec![INIT];
+        init.extend_from_slice(&u32::MAX.to_le_bytes());
+        init.extend_from_slice(&u32::MAX.to_le_bytes());
+        init.extend_from_slice(&[0, 0, 0, 1]);
+        let e = Session::init(&mut &init[..]).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut init = vec![INIT];
+        init.extend_from_slice(&1u32.to_le_bytes());
+        init.extend_from_slice(&1u32.to_le_bytes());
+        init.extend_from_slice(&[0; 7]);
+        let mut session = Session::init(&mut &init[..]).unwrap();
+        let polygons =
+            CowVec::from(vec![triangle([(0, 0), (3, 0), (0, 3)], BLACK)]);
+        session.keep(1, polygons);
+
+        let mut full = Vec::new();
+        full.extend_from_slice(&2u64.to_le_bytes());
+        full.extend_from_slice(&u32::MAX.to_le_bytes());
+        let e = session.request(&mut &full[..], FULL).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // Diffs of a genome one polygon long: more changes than polygons
+        // and a change out of the genome.
+        for (n, index) in &[(u32::MAX, 0u32), (1, 5)] {
+            let mut eval = Vec::new();
+            eval.extend_from_slice(&3u64.to_le_bytes());
+            eval.extend_from_slice(&1u64.to_le_bytes());
+            eval.extend_from_slice(&1u32.to_le_bytes());
+            eval.extend_from_slice(&n.to_le_bytes());
+            eval.extend_from_slice(&index.to_le_bytes());
+            put_polygon(&mut eval, &triangle([(0, 0), (1, 0), (0, 1)], BLACK))
+                .unwrap();
+            let e = session.request(&mut &eval[..], EVAL).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use super::draw::*;
+use super::grid::Grid;
+use rayon::prelude::*;
+use std::ops::Index;
+
+/// Rows in a band of the parallel renderer.
+const BAND: u32 = 32;
+/// Pixels processed at once by the vectorized error kernel.
+const LANES: usize = 8;
+
+///
+/// How per-pixel error is computed: one pixel at a time, in blocks the
+/// compiler vectorizes, or both with results checked to be the same.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kernel {
+    Scalar,
+    Simd,
+    Check,
+}
+
+impl std::str::FromStr for Kernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scalar" => Ok(Kernel::Scalar),
+            "simd" => Ok(Kernel::Simd),
+            "check" => Ok(Kernel::Check),
+            _ => Err(format!("unknown kernel {:?}", s)),
+        }
+    }
+}
+
+///
+/// Running sums of colors of polygons covering every pixel. Color of a pixel
+/// is the average of them, or background if there are none. Canvas may be
+/// a part of a bigger image starting at (`left`, `top`).
+///
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    left: i32,
+    top: i32,
+    bg: Color,
+    sums: Vec<[u32; 3]>,
+    counts: Vec<u32>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32, bg: Color) -> Self {
+        Canvas::band(width, 0, height, bg)
+    }
+
+    pub fn band(width: u32, top: i32, height: u32, bg: Color) -> Self {
+        Canvas::rect(0, top, width, height, bg)
+    }
+
+    pub fn rect(
+        left: i32,
+        top: i32,
+        width: u32,
+        height: u32,
+        bg: Color,
+    ) -> Self {
+        let size = (width * height) as usize;
+
+        Canvas {
+            width,
+            height,
+            left,
+            top,
+            bg,
+            sums: vec![[0; 3]; size],
+            counts: vec![0; size],
+        }
+    }
+
+    /// Points of `p` inside the canvas with their pixel indexes.
+    fn points<'a>(
+        &self,
+        p: &'a Polygon,
+    ) -> impl Iterator<Item = (usize, i32, i32)> + 'a {
+        let (left, top) = (self.left, self.top);
+        let width = self.width as i32;
+        let (right, bottom) = (left + width, top + self.height as i32);
+
+        p.iter_points(right, bottom)
+            .filter(move |(x, y)| *x >= left && *y >= top)
+            .map(move |(x, y)| (((y - top) * width + x - left) as usize, x, y))
+    }
+
+    pub fn add(&mut self, p: &Polygon) {
+        for (i, x, y) in self.points(p) {
+            let c = p.color_at(x, y);
+            for ch in 0..3 {
+                self.sums[i][ch] += c[ch] as u32;
+            }
+            self.counts[i] += 1;
+        }
+    }
+
+    pub fn remove(&mut self, p: &Polygon) {
+        for (i, x, y) in self.points(p) {
+            let c = p.color_at(x, y);
+            for ch in 0..3 {
+                self.sums[i][ch] -= c[ch] as u32;
+            }
+            self.counts[i] -= 1;
+        }
+    }
+
+    /// Color of pixel with index `i` (row major).
+    pub fn color(&self, i: usize) -> image::Rgb<u8> {
+        match self.counts[i] {
+            0 => self.bg.0,
+            n => {
+                let [r, g, b] = self.sums[i];
+                image::Rgb([(r / n) as u8, (g / n) as u8, (b / n) as u8])
+            }
+        }
+    }
+
+    ///
+    /// Color of pixel `i` if a polygon painting it with `c` is removed.
+    ///
+    pub fn color_without(&self, i: usize, c: image::Rgb<u8>) -> image::Rgb<u8> {
+        match self.counts[i] {
+            0 | 1 => self.bg.0,
+            n => {
+                let avg = |ch: usize| {
+                    ((self.sums[i][ch] - c[ch] as u32) / (n - 1)) as u8
+                };
+                image::Rgb([avg(0), avg(1), avg(2)])
+            }
+        }
+    }
+
+    /// Write colors of all pixels into raw RGB buffer.
+    fn paint(&self, buf: &mut [u8]) {
+        for (i, px) in buf.chunks_mut(3).enumerate() {
+            px.copy_from_slice(&self.color(i).0);
+        }
+    }
+}
+
+///
+/// Paint polygons in one thread, for use inside already parallel code such
+/// as fitness evaluation.
+///
+pub fn draw<'a, I>(
+    width: u32,
+    height: u32,
+    bg: Color,
+    polygons: I,
+) -> image::RgbImage
+where
+    I: IntoIterator<Item = &'a Polygon>,
+{
+    let mut canvas = Canvas::new(width, height, bg);
+    for p in polygons {
+        canvas.add(p);
+    }
+
+    let mut img = image::RgbImage::new(width, height);
+    canvas.paint(&mut img);
+    img
+}
+
+///
+/// Paint polygons splitting the image into bands of rows drawn in parallel.
+/// Result is the same as of `draw`.
+///
+pub fn draw_par(
+    width: u32,
+    height: u32,
+    bg: Color,
+    polygons: &[Polygon],
+) -> image::RgbImage {
+    let grid = Grid::new(width as i32, height as i32, polygons);
+    draw_grid(width, height, bg, polygons, &grid)
+}
+
+///
+/// Same as `draw_par` for polygons already indexed by `grid`, every band
+/// only visits polygons from grid cells of its rows.
+///
+pub fn draw_grid<P>(
+    width: u32,
+    height: u32,
+    bg: Color,
+    polygons: &P,
+    grid: &Grid,
+) -> image::RgbImage
+where
+    P: Index<usize, Output = Polygon> + Sync + ?Sized,
+{
+    let mut img = image::RgbImage::new(width, height);
+    let band = (width * BAND * 3) as usize;
+
+    img.par_chunks_mut(band.max(1))
+        .enumerate()
+        .for_each(|(n, buf)| {
+            let top = (n as u32 * BAND) as i32;
+            let rows = (buf.len() / 3) as u32 / width;
+            let mut canvas = Canvas::band(width, top, rows, bg);
+
+            for i in grid.rows(top, top + rows as i32) {
+                canvas.add(&polygons[i]);
+            }
+            canvas.paint(buf);
+        });
+    img
+}
+
+///
+/// Paint `width` x `height` pixels of the image starting at (`left`, `top`),
+/// visiting only polygons from grid cells under them.
+///
+pub fn draw_rect<P>(
+    (left, top): (i32, i32),
+    (width, height): (u32, u32),
+    bg: Color,
+    polygons: &P,
+    grid: &Grid,
+) -> image::RgbImage
+where
+    P: Index<usize, Output = Polygon> + ?Sized,
+{
+    let mut canvas = Canvas::rect(left, top, width, height, bg);
+    let (right, bottom) = (left + width as i32, top + height as i32);
+    for i in grid.rect(left, top, right, bottom) {
+        canvas.add(&polygons[i]);
+    }
+
+    let mut img = image::RgbImage::new(width, height);
+    canvas.paint(&mut img);
+    img
+}
+
+/// Distance between colors, per pixel term of colorize fitness.
+pub fn distance(a: image::Rgb<u8>, b: image::Rgb<u8>) -> f64 {
+    let d_r = (a[0] as i32 - b[0] as i32) as f64;
+    let d_g = (a[1] as i32 - b[1] as i32) as f64;
+    let d_b = (a[2] as i32 - b[2] as i32) as f64;
+
+    (d_r * d_r + d_g * d_g + d_b * d_b).sqrt()
+}
+
+fn distances_scalar(a: &[u8], b: &[u8], mut f: impl FnMut(f64)) {
+    for (a, b) in a.chunks_exact(3).zip(b.chunks_exact(3)) {
+        f(distance(
+            image::Rgb([a[0], a[1], a[2]]),
+            image::Rgb([b[0], b[1], b[2]]),
+        ));
+    }
+}
+
+///
+/// Same as `distances_scalar`, but squares are summed for `LANES` pixels at
+/// once in a structure of arrays layout, so that both they and square roots
+/// are vectorized. Every value is rounded as in the scalar path, so the
+/// results are bit for bit the same.
+///
+fn distances_simd(a: &[u8], b: &[u8], mut f: impl FnMut(f64)) {
+    let mut blocks_a = a.chunks_exact(3 * LANES);
+    let mut blocks_b = b.chunks_exact(3 * LANES);
+
+    for (a, b) in (&mut blocks_a).zip(&mut blocks_b) {
+        let mut d = [0f64; LANES];
+        for ch in 0..3 {
+            for (l, d) in d.iter_mut().enumerate() {
+                let x = (a[3 * l + ch] as i32 - b[3 * l + ch] as i32) as f64;
+                *d += x * x;
+            }
+        }
+        for d in &mut d {
+            *d = d.sqrt();
+        }
+        for d in &d {
+            f(*d);
+        }
+    }
+    distances_scalar(blocks_a.remainder(), blocks_b.remainder(), f);
+}
+
+fn checked<T>(kernel: Kernel, run: impl Fn(Kernel) -> T) -> T
+where
+    T: PartialEq + std::fmt::Debug,
+{
+    match kernel {
+        Kernel::Check => {
+            let (scalar, simd) = (run(Kernel::Scalar), run(Kernel::Simd));
+            assert_eq!(scalar, simd, "scalar and simd kernels differ");
+            scalar
+        }
+        kernel => run(kernel),
+    }
+}
+
+fn distances(a: &[u8], b: &[u8], kernel: Kernel, f: impl FnMut(f64)) {
+    match kernel {
+        Kernel::Scalar => distances_scalar(a, b, f),
+        _ => distances_simd(a, b, f),
+    }
+}
+
+///
+/// Sum of `distance` over pixels of raw RGB buffers, colorize fitness.
+///
+pub fn error(a: &[u8], b: &[u8], kernel: Kernel) -> f64 {
+    checked(kernel, |kernel| {
+        let mut sum = 0.;
+        distances(a, b, kernel, |d| sum += d);
+        sum
+    })
+}
+
+///
+/// Sum of `distance` truncated to integer over pixels of raw RGB buffers,
+/// polygon score.
+///
+pub fn score(a: &[u8], b: &[u8], kernel: Kernel) -> u64 {
+    checked(kernel, |kernel| {
+        let mut sum = 0;
+        distances(a, b, kernel, |d| sum += d as u64);
+        sum
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::random_triangle;
+    use crate::randrange;
+
+    #[test]
+    fn draw_par_matches_draw() {
+        let (width, height) = (100, 70);
+        let bg = Color(image::Rgb([1, 2, 3]));
+        let polygons = c![random_triangle(width, height, 20), for _i in 0..200];
+
+        let par = draw_par(width as u32, height as u32, bg, &polygons);
+        let seq = draw(width as u32, height as u32, bg, &polygons);
+        assert_eq!(*par, *seq);
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        for pixels in 0..=4 * LANES + 3 {
+            let a = c![randrange(0, 256) as u8, for _i in 0..3 * pixels];
+            let b = c![randrange(0, 256) as u8, for _i in 0..3 * pixels];
+
+            let (mut scalar, mut simd) = (Vec::new(), Vec::new());
+            distances_scalar(&a, &b, |d| scalar.push(d));
+            distances_simd(&a, &b, |d| simd.push(d));
+            assert_eq!(scalar.len(), pixels);
+            assert_eq!(scalar, simd);
+
+            assert_eq!(
+                error(&a, &b, Kernel::Scalar),
+                error(&a, &b, Kernel::Simd)
+            );
+            assert_eq!(
+                score(&a, &b, Kernel::Scalar),
+                score(&a, &b, Kernel::Simd)
+            );
+        }
+    }
+}
@@ -1,86 +1,460 @@
+use super::cow::CowVec;
+use super::draw::Polygon;
+use super::history::{self, HallOfFame, Record, Recorder};
+use super::randrange;
+use super::remote::{self, Pool};
+use super::strategy::{Strategy, Survival};
 use rayon::iter::*;
 use rayon::slice::ParallelSliceMut;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+static IDS: AtomicU64 = AtomicU64::new(0);
+
+/// Stopping conditions of a single `Mutation::select` run.
+#[derive(Debug, Clone, Copy)]
+pub struct Stop {
+    /// Number of generations in a row without improvement.
+    pub retries: i32,
+    /// Hard limit on the number of generations.
+    pub max_gens: Option<u64>,
+    /// Improvements of fitness not bigger than this count as stagnation.
+    pub epsilon: f64,
+}
+
+impl Stop {
+    pub fn stalled(&self, last_fitness: Option<f64>, fitness: f64) -> bool {
+        last_fitness.is_some_and(|last| last - fitness <= self.epsilon)
+    }
+
+    /// Whether generation `ngen` is the last one allowed.
+    pub fn done(&self, ngen: u64) -> bool {
+        self.max_gens.is_some_and(|max| ngen >= max)
+    }
+}
 
 pub trait Selection<A: Send + Sync + Clone>:
     Send + Sync + Clone + Sized
 {
     fn mutate(&self) -> Self;
+    /// Combine two parents. Copies `self` if crossover is not supported.
+    fn crossover(&self, _other: &Self) -> Self {
+        self.clone()
+    }
+    /// Same as `mutate`, but fitness of the child is left to `set_fitness`
+    /// or `evaluate`, for children evaluated elsewhere.
+    fn mutate_unscored(&self) -> Self {
+        self.mutate()
+    }
+    /// Same as `crossover`, fitness is left as by `mutate_unscored`.
+    fn crossover_unscored(&self, other: &Self) -> Self {
+        self.crossover(other)
+    }
+    /// Set fitness computed elsewhere.
+    fn set_fitness(&mut self, _fitness: f64) {}
+    /// Compute fitness of a child made without it.
+    fn evaluate(&mut self) {}
     fn generate(args: A) -> Self;
-    fn fitness(&self) -> u64;
-    fn print(&self, ngen: u64, fitness: u64);
+    /// Number of polygons changed by `mutate`.
+    fn scale(&self) -> usize;
+    fn set_scale(&mut self, scale: usize);
+    /// Fitness to be minimized.
+    fn fitness(&self) -> f64;
+    fn print(&self, ngen: u64, fitness: f64);
+    /// How different two individuals are, from 0 for equal ones to 1.
+    fn distance(&self, _other: &Self) -> f64 {
+        0.
+    }
+    /// Save individual as an image.
+    fn save(&self, _path: &Path) {}
+    /// Estimated memory taken by one individual in bytes.
+    fn size_hint(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+    /// Estimated memory taken by one individual with parts shared with
+    /// others counted too.
+    fn full_size(&self) -> usize {
+        self.size_hint()
+    }
+    /// Polygons colorize fitness is computed from, if it can be evaluated
+    /// by remote workers.
+    fn polygons(&self) -> Option<&CowVec<Polygon>> {
+        None
+    }
+}
+
+///
+/// Adaptive control of mutation scale by 1/5 success rule: if more than
+/// `target` share of children are better than their parents, mutations get
+/// bigger by `factor`, otherwise smaller.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Adapt {
+    pub target: f64,
+    pub factor: f64,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Adapt {
+    pub fn update(
+        &self,
+        scale: usize,
+        successes: usize,
+        trials: usize,
+    ) -> usize {
+        let rate = successes as f64 / trials.max(1) as f64;
+        let scale = if rate > self.target {
+            (scale as f64 * self.factor).ceil()
+        } else {
+            (scale as f64 / self.factor).floor()
+        };
+
+        (scale as usize).max(self.min).min(self.max)
+    }
+}
+
+/// Settings of evolution shared by all runs.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Probability of a child being made by crossover of two parents.
+    pub crossover: f64,
+    pub strategy: Arc<dyn Strategy>,
+    /// Initial population size (μ), grows by one every stagnant generation.
+    pub population: usize,
+    /// Number of children per generation (λ), square of population size
+    /// if not set.
+    pub offspring: Option<usize>,
+    /// Adapt mutation scale instead of growing population on stagnation.
+    pub adapt: Option<Adapt>,
+    pub max_population: Option<usize>,
+    pub max_offspring: Option<usize>,
+    /// Population and offspring are throttled to fit into this many bytes.
+    pub memory_budget: Option<usize>,
+    /// Statistics of every generation are written here.
+    pub history: Option<Arc<Recorder>>,
+    /// Number of best distinct individuals of every run to save into
+    /// `hall/run-NNN/`.
+    pub hall_of_fame: usize,
+    /// Workers evaluating fitness of children instead of this process.
+    pub remote: Option<Arc<Pool>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Mutation<Args: Send + Sync + Clone, DNA: Selection<Args>> {
-    dna: DNA,
-    fitness: u64,
+    pub dna: DNA,
+    pub fitness: f64,
+    /// Unique id, remote workers refer to individuals by it.
+    id: u64,
     marker: std::marker::PhantomData<Args>,
 }
 
-impl<Args: Send + Sync + Clone, DNA: Selection<Args>> Mutation<Args, DNA> {
-    fn new_gen(&self, nmuts: usize) -> Vec<Mutation<Args, DNA>> {
-        let mutations = c![self.dna.mutate(), for _i in 0..nmuts];
-
-        let mut mutations = mutations
-            .par_iter()
-            .map(|dna| Self {
-                dna: dna.clone(),
-                fitness: dna.fitness(),
-                marker: std::marker::PhantomData,
-            })
-            .collect::<Vec<Self>>();
+/// Population sorted by fitness.
+pub struct Population<Args: Send + Sync + Clone, DNA: Selection<Args>> {
+    pub gen: Vec<Mutation<Args, DNA>>,
+    /// Population size.
+    pub mu: usize,
+    pub ngen: u64,
+}
 
-        mutations.par_sort_by_key(|it| it.fitness);
-        mutations[..nmuts].to_vec()
+impl<Args: Send + Sync + Clone, DNA: Selection<Args>> Mutation<Args, DNA> {
+    fn new(dna: DNA) -> Self {
+        Mutation {
+            fitness: dna.fitness(),
+            dna,
+            id: IDS.fetch_add(1, Ordering::Relaxed),
+            marker: std::marker::PhantomData,
+        }
     }
 
-    pub fn select(args: Args, retries: i32) -> DNA {
-        let parent = {
-            let dna = DNA::generate(args);
-            let fitness = dna.fitness();
+    ///
+    /// Child of this individual, possibly crossed with one of `mates`. It is
+    /// not scored unless `scored`.
+    ///
+    fn child(&self, mates: &[Self], crossover: f64, scored: bool) -> DNA {
+        let mate = if mates.len() > 1 && randrange(0., 1.) < crossover {
+            Some(&mates[randrange(0, mates.len())].dna)
+        } else {
+            None
+        };
 
-            Mutation {
-                dna,
-                fitness,
-                marker: std::marker::PhantomData,
+        match (mate, scored) {
+            (Some(mate), true) => self.dna.crossover(mate).mutate(),
+            (Some(mate), false) => {
+                self.dna.crossover_unscored(mate).mutate_unscored()
             }
-        };
-        let mut nmuts = 10;
+            (None, true) => self.dna.mutate(),
+            (None, false) => self.dna.mutate_unscored(),
+        }
+    }
+
+    pub fn select(args: Args, stop: Stop, config: &Config) -> DNA {
+        let mut pop = Population::<Args, DNA>::new(args, config);
         let mut fails = 0;
-        let mut gen = parent.new_gen(nmuts);
-        let mut last_fitness = 1_000_000_000;
-        let mut ngen = 0;
+        let mut last_fitness = None;
+        let run = history::next_run();
+        let mut hall = HallOfFame::new(config.hall_of_fame);
 
         loop {
-            ngen += 1;
-
-            let mut kids = gen.par_iter().map(|p| p.new_gen(nmuts)).reduce(
-                || Vec::new(),
-                |mut a: Vec<Self>, mut b: Vec<Self>| {
-                    a.append(&mut b);
-                    a
-                },
-            );
-            kids.append(&mut gen);
-            kids.par_sort_by_key(|it| it.fitness);
-            gen = kids[..nmuts].to_vec();
-
-            let min_kid = &gen[0];
-            if min_kid.fitness == last_fitness {
-                nmuts += 1;
-                fails += 1;
-                println!("nmuts {}", nmuts);
+            let start = Instant::now();
+            pop.step(config);
+            pop.observe(config, &run.to_string(), start, &mut hall);
+
+            let fitness = pop.best().fitness;
+            if stop.stalled(last_fitness, fitness) {
+                if config.adapt.is_none() {
+                    pop.grow(config);
+                    println!("nmuts {}", pop.mu);
+                }
+                if pop.settled(config) {
+                    fails += 1;
+                }
             } else {
                 fails = 0;
             }
-            if fails == retries {
+            if fails == stop.retries {
+                break;
+            }
+            pop.best().dna.print(pop.ngen, fitness);
+            last_fitness = Some(fitness);
+
+            if stop.done(pop.ngen) {
                 break;
             }
-            min_kid.dna.print(ngen, min_kid.fitness);
-            last_fitness = min_kid.fitness;
         }
 
-        let min_kid = &gen[0];
-        min_kid.dna.clone()
+        if config.hall_of_fame > 0 {
+            hall.export(format!("hall/run-{:03}", run)).unwrap();
+        }
+        pop.best().dna.clone()
+    }
+}
+
+impl<Args: Send + Sync + Clone, DNA: Selection<Args>> Population<Args, DNA> {
+    ///
+    /// Population of `config.population` mutants of generated DNA.
+    ///
+    pub fn new(args: Args, config: &Config) -> Self {
+        let parent = DNA::generate(args);
+        let mut mu = config.population.max(1);
+        if let Some(max) = config.max_population {
+            mu = mu.min(max.max(1));
+        }
+        let mut gen = (0..mu)
+            .into_par_iter()
+            .map(|_| Mutation::new(parent.mutate()))
+            .collect::<Vec<_>>();
+        gen.par_sort_by(|a, b| a.fitness.total_cmp(&b.fitness));
+
+        Population { gen, mu, ngen: 0 }
+    }
+
+    pub fn best(&self) -> &Mutation<Args, DNA> {
+        &self.gen[0]
+    }
+
+    ///
+    /// Add individuals from elsewhere, they replace the worst ones.
+    ///
+    pub fn immigrate(&mut self, mut migrants: Vec<Mutation<Args, DNA>>) {
+        self.gen.append(&mut migrants);
+        self.gen.par_sort_by(|a, b| a.fitness.total_cmp(&b.fitness));
+        self.gen.truncate(self.mu);
+    }
+
+    ///
+    /// Breed children of parents chosen by strategy and keep `mu` best of
+    /// those allowed to survive.
+    ///
+    pub fn step(&mut self, config: &Config) {
+        let lambda = self.throttle(config);
+        let fitness = c![m.fitness, for m in &self.gen];
+        let mut parents = config.strategy.parents(&fitness, lambda);
+        if parents.is_empty() {
+            // Without children comma survival would leave nobody alive.
+            parents.push(0);
+        }
+
+        let gen = &self.gen;
+        let mut kids = match &config.remote {
+            Some(pool) if pool.alive() => {
+                self.breed_remote(pool, &parents, config)
+            }
+            _ => parents
+                .par_iter()
+                .map(|i| {
+                    Mutation::new(gen[*i].child(gen, config.crossover, true))
+                })
+                .collect::<Vec<_>>(),
+        };
+        let successes = kids
+            .iter()
+            .zip(&parents)
+            .filter(|(kid, i)| kid.fitness < gen[**i].fitness)
+            .count();
+
+        match config.strategy.survival() {
+            Survival::Plus => kids.append(&mut self.gen),
+            Survival::Comma { elite } => {
+                self.gen.truncate(elite);
+                kids.append(&mut self.gen);
+            }
+        }
+        kids.par_sort_by(|a, b| a.fitness.total_cmp(&b.fitness));
+        kids.truncate(self.mu);
+
+        if let Some(adapt) = &config.adapt {
+            let scale = adapt.update(kids[0].dna.scale(), successes, lambda);
+            if scale != kids[0].dna.scale() {
+                println!("scale {}", scale);
+            }
+            for kid in &mut kids {
+                kid.dna.set_scale(scale);
+            }
+        }
+
+        self.gen = kids;
+        self.ngen += 1;
+    }
+
+    ///
+    /// Breed children without scoring them and evaluate them on remote
+    /// workers. Children no worker could evaluate are scored locally.
+    ///
+    fn breed_remote(
+        &self,
+        pool: &Pool,
+        parents: &[usize],
+        config: &Config,
+    ) -> Vec<Mutation<Args, DNA>> {
+        let gen = &self.gen;
+        let mut dnas = parents
+            .par_iter()
+            .map(|i| gen[*i].child(gen, config.crossover, false))
+            .collect::<Vec<_>>();
+        let ids = c![IDS.fetch_add(1, Ordering::Relaxed), for _dna in &dnas];
+
+        let mut jobs = Vec::with_capacity(dnas.len());
+        for ((dna, i), id) in dnas.iter().zip(parents).zip(&ids) {
+            let parent = &gen[*i];
+            if let (Some(polygons), Some(parent_polygons)) =
+                (dna.polygons(), parent.dna.polygons())
+            {
+                jobs.push(remote::Job {
+                    id: *id,
+                    parent: parent.id,
+                    parent_polygons,
+                    polygons,
+                });
+            }
+        }
+
+        let fitness = if jobs.len() == dnas.len() {
+            pool.evaluate(&jobs)
+        } else {
+            vec![None; dnas.len()]
+        };
+        drop(jobs);
+        dnas.par_iter_mut().zip(fitness).for_each(
+            |(dna, fitness)| match fitness {
+                Some(fitness) => dna.set_fitness(fitness),
+                None => dna.evaluate(),
+            },
+        );
+
+        dnas.into_iter()
+            .zip(ids)
+            .map(|(dna, id)| Mutation {
+                fitness: dna.fitness(),
+                dna,
+                id,
+                marker: std::marker::PhantomData,
+            })
+            .collect()
+    }
+
+    ///
+    /// Write statistics of the last generation started at `start` into
+    /// history and offer its individuals to hall of fame.
+    ///
+    pub fn observe(
+        &self,
+        config: &Config,
+        run: &str,
+        start: Instant,
+        hall: &mut HallOfFame<DNA>,
+    ) {
+        for m in &self.gen {
+            hall.offer(m.fitness, &m.dna);
+        }
+
+        let recorder = match &config.history {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        recorder.write(&Record {
+            run: run.to_string(),
+            ngen: self.ngen,
+            best: self.best().fitness,
+            median: self.gen[self.gen.len() / 2].fitness,
+            worst: self.gen.last().unwrap().fitness,
+            diversity: history::diversity(&self.gen, |a, b| {
+                a.dna.distance(&b.dna)
+            }),
+            mu: self.mu,
+            scale: self.best().dna.scale(),
+            millis: start.elapsed().as_millis(),
+        });
+    }
+
+    ///
+    /// Grow population by one unless it is at its limit.
+    ///
+    pub fn grow(&mut self, config: &Config) {
+        if config.max_population.is_none_or(|max| self.mu < max) {
+            self.mu += 1;
+        }
+    }
+
+    ///
+    /// Number of children of the next generation, at least one, with
+    /// population size shrunk if needed to stay within memory budget.
+    ///
+    fn throttle(&mut self, config: &Config) -> usize {
+        let mut lambda = config.offspring.unwrap_or(self.mu * self.mu);
+        if let Some(max) = config.max_offspring {
+            lambda = lambda.min(max);
+        }
+
+        if let Some(budget) = config.memory_budget {
+            // Parts shared by all individuals are taken once.
+            let best = &self.best().dna;
+            let fit = budget.saturating_sub(best.full_size())
+                / best.size_hint().max(1);
+            if self.mu + lambda > fit {
+                // Survivors are kept along with children, so at least two
+                // individuals have to fit.
+                self.mu = self.mu.min(fit / 2).max(1);
+                lambda = fit.saturating_sub(self.mu).max(1);
+                println!(
+                    "memory budget: population {} offspring {}",
+                    self.mu, lambda
+                );
+            }
+        }
+
+        lambda.max(1)
+    }
+
+    ///
+    /// Whether mutation scale can't get any smaller, so stagnation means
+    /// that population is stuck.
+    ///
+    pub fn settled(&self, config: &Config) -> bool {
+        config
+            .adapt
+            .is_none_or(|adapt| self.best().dna.scale() <= adapt.min)
     }
 }
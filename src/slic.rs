@@ -0,0 +1,424 @@
+use super::draw::*;
+
+/// SLIC iterations, it usually converges in about ten.
+const ITERATIONS: usize = 10;
+/// Segment hulls are simplified to at most this many vertices.
+const MAX_SIDES: usize = 8;
+
+///
+/// Superpixels of an image: compact regions of similar color.
+///
+#[derive(Debug, Clone)]
+pub struct Segments {
+    width: u32,
+    /// Segment of every pixel.
+    labels: Vec<usize>,
+    means: Vec<Color>,
+    pixels: Vec<Vec<Point>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Center {
+    x: f64,
+    y: f64,
+    rgb: [f64; 3],
+}
+
+///
+/// Segment image into about `n` superpixels by SLIC: k-means over color and
+/// position, with every center looking only at pixels near it.
+/// `compactness` trades color similarity for regular shapes.
+///
+pub fn slic(img: &image::RgbImage, n: usize, compactness: f64) -> Segments {
+    let (width, height) = img.dimensions();
+    let step = ((width * height) as f64 / n.max(1) as f64).sqrt().max(1.);
+    let rgb = |x: u32, y: u32| {
+        let px = img.get_pixel(x, y);
+        [px[0] as f64, px[1] as f64, px[2] as f64]
+    };
+
+    let mut centers = Vec::new();
+    let mut y = step / 2.;
+    while y < height as f64 {
+        let mut x = step / 2.;
+        while x < width as f64 {
+            centers.push(Center {
+                x,
+                y,
+                rgb: rgb(x as u32, y as u32),
+            });
+            x += step;
+        }
+        y += step;
+    }
+
+    let size = (width * height) as usize;
+    let mut labels = vec![0; size];
+    let weight = (compactness / step).powi(2);
+
+    for _ in 0..ITERATIONS {
+        let mut dists = vec![f64::INFINITY; size];
+
+        for (k, c) in centers.iter().enumerate() {
+            let (x0, x1) =
+                ((c.x - step).max(0.), (c.x + step).min(width as f64));
+            let (y0, y1) =
+                ((c.y - step).max(0.), (c.y + step).min(height as f64));
+
+            for y in y0 as u32..y1 as u32 {
+                for x in x0 as u32..x1 as u32 {
+                    let px = rgb(x, y);
+                    let dc: f64 =
+                        (0..3).map(|i| (px[i] - c.rgb[i]).powi(2)).sum();
+                    let ds =
+                        (x as f64 - c.x).powi(2) + (y as f64 - c.y).powi(2);
+                    let d = dc + ds * weight;
+
+                    let i = (y * width + x) as usize;
+                    if d < dists[i] {
+                        dists[i] = d;
+                        labels[i] = k;
+                    }
+                }
+            }
+        }
+
+        let mut sums = vec![[0f64; 6]; centers.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let s = &mut sums[labels[(y * width + x) as usize]];
+                let px = rgb(x, y);
+                s[0] += x as f64;
+                s[1] += y as f64;
+                for i in 0..3 {
+                    s[2 + i] += px[i];
+                }
+                s[5] += 1.;
+            }
+        }
+        for (c, s) in centers.iter_mut().zip(&sums) {
+            if s[5] > 0. {
+                c.x = s[0] / s[5];
+                c.y = s[1] / s[5];
+                c.rgb = [s[2] / s[5], s[3] / s[5], s[4] / s[5]];
+            }
+        }
+    }
+
+    let (labels, n) =
+        connect(&labels, width, height, (step * step) as usize / 4);
+    let mut pixels = vec![Vec::new(); n];
+    let mut sums = vec![[0f64; 3]; n];
+    for y in 0..height {
+        for x in 0..width {
+            let k = labels[(y * width + x) as usize];
+            pixels[k].push(Point {
+                x: x as i32,
+                y: y as i32,
+            });
+            let px = rgb(x, y);
+            for i in 0..3 {
+                sums[k][i] += px[i];
+            }
+        }
+    }
+    let means = c![
+        {
+            let n = pixels[k].len().max(1) as f64;
+            Color(image::Rgb([
+                (sums[k][0] / n).round() as u8,
+                (sums[k][1] / n).round() as u8,
+                (sums[k][2] / n).round() as u8,
+            ]))
+        },
+        for k in 0..n
+    ];
+
+    Segments {
+        width,
+        labels,
+        means,
+        pixels,
+    }
+}
+
+///
+/// Relabel `labels` so that every segment is 4-connected: parts of a segment
+/// smaller than `min_size` are merged into a neighbouring segment, bigger
+/// ones become segments of their own. Returns labels and their number.
+///
+fn connect(
+    labels: &[usize],
+    width: u32,
+    height: u32,
+    min_size: usize,
+) -> (Vec<usize>, usize) {
+    let (w, h) = (width as i32, height as i32);
+    let mut connected = vec![usize::MAX; labels.len()];
+    let mut n = 0;
+
+    for start in 0..labels.len() {
+        if connected[start] != usize::MAX {
+            continue;
+        }
+
+        let mut part = vec![start];
+        let mut adjacent = None;
+        connected[start] = n;
+        let mut i = 0;
+        while i < part.len() {
+            let (x, y) = ((part[i] as i32) % w, (part[i] as i32) / w);
+            for (dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || nx >= w || ny < 0 || ny >= h {
+                    continue;
+                }
+                let j = (ny * w + nx) as usize;
+                if connected[j] == usize::MAX && labels[j] == labels[start] {
+                    connected[j] = n;
+                    part.push(j);
+                } else if connected[j] != usize::MAX && connected[j] != n {
+                    adjacent = Some(connected[j]);
+                }
+            }
+            i += 1;
+        }
+
+        match adjacent {
+            Some(k) if part.len() < min_size => {
+                for i in part {
+                    connected[i] = k;
+                }
+            }
+            _ => n += 1,
+        }
+    }
+    (connected, n)
+}
+
+fn cross(o: Point, a: Point, b: Point) -> i64 {
+    (a.x - o.x) as i64 * (b.y - o.y) as i64
+        - (a.y - o.y) as i64 * (b.x - o.x) as i64
+}
+
+/// Convex hull by monotone chain, counterclockwise.
+fn hull(mut points: Vec<Point>) -> Vec<Point> {
+    points.sort_by_key(|p| (p.x, p.y));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+
+    let mut hull: Vec<Point> = Vec::new();
+    for pass in 0..2 {
+        let start = hull.len();
+        let iter: Box<dyn Iterator<Item = &Point>> = if pass == 0 {
+            Box::new(points.iter())
+        } else {
+            Box::new(points.iter().rev())
+        };
+
+        for &p in iter {
+            while hull.len() >= start + 2
+                && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0
+            {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        hull.pop();
+    }
+    hull
+}
+
+impl Segments {
+    /// Mean color of the segment of pixel (x, y).
+    pub fn color_at(&self, x: i32, y: i32) -> Option<Color> {
+        let height = (self.labels.len() / self.width.max(1) as usize) as i32;
+        if x < 0 || x >= self.width as i32 || y < 0 || y >= height {
+            return None;
+        }
+        Some(
+            self.means
+                [self.labels[(y as u32 * self.width + x as u32) as usize]],
+        )
+    }
+
+    ///
+    /// Color polygons with the mean of the segment of their center and, with
+    /// `gouraud`, vertices with means of their segments. Parts outside of
+    /// the image get `fallback`.
+    ///
+    pub fn paint(
+        &self,
+        polygons: &mut [Polygon],
+        gouraud: bool,
+        fallback: Color,
+    ) {
+        for p in polygons {
+            let (x, y) = p.center();
+            p.color = self.color_at(x, y).unwrap_or(fallback);
+            if gouraud {
+                let at = |v: Point| self.color_at(v.x, v.y).unwrap_or(p.color);
+                p.vertex_colors =
+                    Some([at(p.points[0]), at(p.points[1]), at(p.points[2])]);
+            }
+        }
+    }
+
+    ///
+    /// Triangles fanned from the centroid of every segment to vertices of
+    /// its simplified convex hull, colored with the segment mean.
+    ///
+    pub fn seed(&self) -> Vec<Polygon> {
+        let mut polygons = Vec::new();
+
+        for (points, &color) in self.pixels.iter().zip(&self.means) {
+            if points.is_empty() {
+                continue;
+            }
+            let n = points.len() as i64;
+            let center = Point {
+                x: (points.iter().map(|p| p.x as i64).sum::<i64>() / n) as i32,
+                y: (points.iter().map(|p| p.y as i64).sum::<i64>() / n) as i32,
+            };
+
+            // Corners of pixels, so that the hull covers whole pixels and
+            // segments one pixel thin still have an area.
+            let corners = points
+                .iter()
+                .flat_map(|p| {
+                    c![Point { x: p.x + dx, y: p.y + dy }, for dx in 0..2, for dy in 0..2]
+                })
+                .collect();
+            let hull = hull(corners);
+            if hull.len() < 3 {
+                continue;
+            }
+            let sides = hull.len().min(MAX_SIDES);
+            let hull = c![hull[i * hull.len() / sides], for i in 0..sides];
+
+            for i in 0..hull.len() {
+                let (a, b) = (hull[i], hull[(i + 1) % hull.len()]);
+                if cross(center, a, b) == 0 {
+                    continue;
+                }
+                polygons.push(Polygon::new([center, a, b], color));
+            }
+        }
+        polygons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: i32, y: i32) -> Point {
+        Point { x, y }
+    }
+
+    /// Segments from labels of a `width` wide image, all of the same color.
+    fn segments(width: u32, labels: Vec<usize>) -> Segments {
+        let n = labels.iter().max().map_or(0, |k| k + 1);
+        let mut pixels = vec![Vec::new(); n];
+        for (i, k) in labels.iter().enumerate() {
+            pixels[*k]
+                .push(point(i as i32 % width as i32, i as i32 / width as i32));
+        }
+
+        Segments {
+            width,
+            labels,
+            means: vec![Color(image::Rgb([0, 0, 0])); n],
+            pixels,
+        }
+    }
+
+    /// Pixels of the segment covered by none of `polygons`.
+    fn uncovered(
+        points: &[Point],
+        polygons: &[Polygon],
+        size: (i32, i32),
+    ) -> usize {
+        points
+            .iter()
+            .filter(|p| {
+                !polygons
+                    .iter()
+                    .any(|t| t.contains(p.x, p.y, size.0, size.1))
+            })
+            .count()
+    }
+
+    #[test]
+    fn hull_of_square_collinear_and_single_points() {
+        let square = c![point(x, y), for x in 0..3, for y in 0..3];
+        assert_eq!(
+            hull(square),
+            vec![point(0, 0), point(2, 0), point(2, 2), point(0, 2)]
+        );
+
+        let line = c![point(x, 1), for x in 0..5];
+        assert_eq!(hull(line), vec![point(0, 1), point(4, 1)]);
+        assert_eq!(hull(vec![point(3, 3); 2]), vec![point(3, 3)]);
+    }
+
+    #[test]
+    fn thin_segments_are_seeded() {
+        // A row, a column and a single pixel segment around a big one.
+        let (width, height) = (6, 5);
+        let labels = c![
+            match (x, y) {
+                (_, 0) => 1,
+                (0, _) => 2,
+                (3, 3) => 3,
+                _ => 0,
+            },
+            for y in 0..height,
+            for x in 0..width
+        ];
+        let segments = segments(width as u32, labels);
+        let polygons = segments.seed();
+
+        for points in &segments.pixels {
+            assert_eq!(uncovered(points, &polygons, (width, height)), 0);
+        }
+    }
+
+    #[test]
+    fn seeds_cover_image() {
+        let img = image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x / 16 * 60) as u8, (y / 12 * 60) as u8, 128])
+        });
+        let segments = slic(&img, 40, 10.);
+        let polygons = segments.seed();
+
+        let all = c![point(x, y), for y in 0..48, for x in 0..64];
+        let uncovered = uncovered(&all, &polygons, (64, 48));
+        assert!(uncovered * 100 <= all.len(), "{} uncovered", uncovered);
+    }
+
+    #[test]
+    fn segments_are_connected() {
+        let img = image::RgbImage::from_fn(64, 48, |x, y| {
+            let v = if (x * 7 + y * 13) % 11 < 5 { 0 } else { 255 };
+            image::Rgb([v, (x * 4) as u8, (y * 5) as u8])
+        });
+        let segments = slic(&img, 30, 5.);
+
+        for (k, points) in segments.pixels.iter().enumerate() {
+            assert!(!points.is_empty());
+            let (labels, n) = connect(
+                &c![(segments.labels[i] == k) as usize, for i in 0..64 * 48],
+                64,
+                48,
+                0,
+            );
+            // The segment and the rest of the image, which may be split by
+            // it into several parts.
+            let parts = c![labels[(p.y * 64 + p.x) as usize], for p in points];
+            assert!(parts.iter().all(|l| *l == parts[0]), "{} of {}", k, n);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use super::randrange;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Who competes for places in the next generation.
+#[derive(Debug, Clone, Copy)]
+pub enum Survival {
+    /// Parents and children, (μ+λ).
+    Plus,
+    /// Children and `elite` best parents, (μ,λ) for `elite` of 0.
+    Comma { elite: usize },
+}
+
+///
+/// Selection scheme of `Population::step`. Fitness is minimized and
+/// population is always sorted by it.
+///
+pub trait Strategy: Send + Sync + Debug {
+    /// Indexes of parents of `lambda` children.
+    fn parents(&self, fitness: &[f64], lambda: usize) -> Vec<usize>;
+    fn survival(&self) -> Survival;
+}
+
+/// Every parent gets the same number of children, best of parents and
+/// children survive.
+#[derive(Debug)]
+pub struct Truncation;
+
+/// Parents are winners of tournaments between `size` random individuals.
+#[derive(Debug)]
+pub struct Tournament {
+    pub size: usize,
+}
+
+/// Parents are picked with probability proportional to how much better
+/// they are than the worst one (plus one, so that it has a chance too).
+#[derive(Debug)]
+pub struct Roulette;
+
+/// (μ+λ): random parents, best of parents and children survive.
+#[derive(Debug)]
+pub struct Plus;
+
+/// (μ,λ): random parents, only children survive.
+#[derive(Debug)]
+pub struct Comma;
+
+impl Strategy for Truncation {
+    fn parents(&self, fitness: &[f64], lambda: usize) -> Vec<usize> {
+        c![i % fitness.len(), for i in 0..lambda]
+    }
+
+    fn survival(&self) -> Survival {
+        Survival::Plus
+    }
+}
+
+impl Strategy for Tournament {
+    fn parents(&self, fitness: &[f64], lambda: usize) -> Vec<usize> {
+        let round = |_| {
+            (0..self.size.max(1))
+                .map(|_| randrange(0, fitness.len()))
+                .min_by(|a, b| fitness[*a].total_cmp(&fitness[*b]))
+                .unwrap()
+        };
+
+        (0..lambda).map(round).collect()
+    }
+
+    fn survival(&self) -> Survival {
+        Survival::Comma { elite: 1 }
+    }
+}
+
+impl Strategy for Roulette {
+    fn parents(&self, fitness: &[f64], lambda: usize) -> Vec<usize> {
+        let worst = fitness.iter().cloned().fold(f64::MIN, f64::max);
+        let weights = c![worst - f + 1., for f in fitness];
+        let total: f64 = weights.iter().sum();
+
+        let spin = |_| {
+            let mut ball = randrange(0., total);
+            for (i, w) in weights.iter().enumerate() {
+                if ball < *w {
+                    return i;
+                }
+                ball -= w;
+            }
+            weights.len() - 1
+        };
+
+        (0..lambda).map(spin).collect()
+    }
+
+    fn survival(&self) -> Survival {
+        Survival::Comma { elite: 1 }
+    }
+}
+
+impl Strategy for Plus {
+    fn parents(&self, fitness: &[f64], lambda: usize) -> Vec<usize> {
+        c![randrange(0, fitness.len()), for _i in 0..lambda]
+    }
+
+    fn survival(&self) -> Survival {
+        Survival::Plus
+    }
+}
+
+impl Strategy for Comma {
+    fn parents(&self, fitness: &[f64], lambda: usize) -> Vec<usize> {
+        c![randrange(0, fitness.len()), for _i in 0..lambda]
+    }
+
+    fn survival(&self) -> Survival {
+        Survival::Comma { elite: 0 }
+    }
+}
+
+///
+/// Parse strategy name: `truncation`, `tournament[:SIZE]`, `roulette`,
+/// `plus` or `comma`.
+///
+pub fn parse(s: &str) -> Result<Arc<dyn Strategy>, String> {
+    let mut it = s.splitn(2, ':');
+    let name = it.next().unwrap();
+    let param = it.next();
+
+    Ok(match (name, param) {
+        ("truncation", None) => Arc::new(Truncation),
+        ("tournament", size) => Arc::new(Tournament {
+            size: size
+                .map_or(Ok(2), |s| s.parse::<usize>())
+                .map_err(|e| e.to_string())?,
+        }),
+        ("roulette", None) => Arc::new(Roulette),
+        ("plus", None) => Arc::new(Plus),
+        ("comma", None) => Arc::new(Comma),
+        _ => return Err(format!("unknown strategy {:?}", s)),
+    })
+}
@@ -0,0 +1,114 @@
+use super::draw::*;
+use std::fmt::Write;
+use std::path::Path;
+
+///
+/// Linear gradient approximating Gouraud shading of a polygon: it goes along
+/// the direction in which luminance changes the most, with a stop for every
+/// vertex.
+///
+fn gradient(p: &Polygon, colors: [Color; 3], id: usize) -> Option<String> {
+    fn luminance(Color(image::Rgb([r, g, b])): Color) -> f64 {
+        0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+    }
+
+    let [p0, p1, p2] = p.points;
+    let (e1, e2) = (p1 - p0, p2 - p0);
+    let l0 = luminance(colors[0]);
+    let (dl1, dl2) = (luminance(colors[1]) - l0, luminance(colors[2]) - l0);
+
+    let det = (e1.x * e2.y - e1.y * e2.x) as f64;
+    if det == 0. {
+        return None;
+    }
+    let a = (dl1 * e2.y as f64 - e1.y as f64 * dl2) / det;
+    let b = (e1.x as f64 * dl2 - dl1 * e2.x as f64) / det;
+    let norm = (a * a + b * b).sqrt();
+    if norm < 1e-9 {
+        return None;
+    }
+    let (a, b) = (a / norm, b / norm);
+
+    let t = c![(pt.x as f64) * a + (pt.y as f64) * b, for pt in &p.points];
+    let t_min = t.iter().cloned().fold(f64::INFINITY, f64::min);
+    let t_max = t.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let start = p.points[t.iter().position(|&v| v == t_min).unwrap()];
+    let len = t_max - t_min;
+
+    let mut stops = c![((t[i] - t_min) / len, colors[i]), for i in 0..3];
+    stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut s = String::new();
+    write!(
+        s,
+        r#"<linearGradient id="g{}" gradientUnits="userSpaceOnUse" x1="{}" y1="{}" x2="{:.2}" y2="{:.2}">"#,
+        id,
+        start.x,
+        start.y,
+        start.x as f64 + a * len,
+        start.y as f64 + b * len,
+    )
+    .unwrap();
+    for (offset, color) in stops {
+        write!(
+            s,
+            r#"<stop offset="{:.4}" stop-color="{}"/>"#,
+            offset,
+            color.hex()
+        )
+        .unwrap();
+    }
+    s.push_str("</linearGradient>");
+
+    Some(s)
+}
+
+///
+/// Save polygons as SVG image. Note that renderer averages colors of
+/// overlapping polygons while SVG paints them one over another, so the
+/// result is only an approximation of `render::draw`.
+///
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    bg: Color,
+    polygons: &[Polygon],
+) -> std::io::Result<()> {
+    let mut defs = String::new();
+    let mut body = String::new();
+
+    for (id, p) in polygons.iter().enumerate() {
+        let fill = match p.vertex_colors.and_then(|c| gradient(p, c, id)) {
+            Some(g) => {
+                defs.push_str(&g);
+                format!("url(#g{})", id)
+            }
+            None => p.color.hex(),
+        };
+        let [p0, p1, p2] = p.points;
+
+        writeln!(
+            body,
+            r#"<polygon points="{},{} {},{} {},{}" fill="{}"/>"#,
+            p0.x, p0.y, p1.x, p1.y, p2.x, p2.y, fill
+        )
+        .unwrap();
+    }
+
+    let svg = format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            "\n<defs>{defs}</defs>\n",
+            r#"<rect width="{w}" height="{h}" fill="{bg}"/>"#,
+            "\n{body}</svg>\n"
+        ),
+        w = width,
+        h = height,
+        defs = defs,
+        bg = bg.hex(),
+        body = body,
+    );
+
+    std::fs::write(path, svg)
+}
@@ -0,0 +1,535 @@
+use super::{draw::*, grid::Grid, png, render};
+use image::png::PngDecoder;
+use image::{ImageDecoder, ImageResult};
+use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+///
+/// Part of the image optimized on its own. Tiles overlap their neighbours,
+/// each one owns only its core, a cell of the regular grid.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Core in image coordinates, unbounded at image borders.
+    core: (i32, i32, i32, i32),
+}
+
+///
+/// Split image into tiles with cores of `size` pixels extended by `overlap`
+/// pixels on every side.
+///
+pub fn tiles(width: u32, height: u32, size: u32, overlap: u32) -> Vec<Tile> {
+    let size = size.max(1);
+    let mut tiles = Vec::new();
+
+    for y0 in (0..height).step_by(size as usize) {
+        for x0 in (0..width).step_by(size as usize) {
+            let (x1, y1) = ((x0 + size).min(width), (y0 + size).min(height));
+            let (x, y) =
+                (x0.saturating_sub(overlap), y0.saturating_sub(overlap));
+            let edge = |v: u32, at: u32, unbounded: i32| {
+                if v == at {
+                    unbounded
+                } else {
+                    v as i32
+                }
+            };
+
+            tiles.push(Tile {
+                x,
+                y,
+                width: (x1 + overlap).min(width) - x,
+                height: (y1 + overlap).min(height) - y,
+                core: (
+                    edge(x0, 0, i32::MIN),
+                    edge(y0, 0, i32::MIN),
+                    edge(x1, width, i32::MAX),
+                    edge(y1, height, i32::MAX),
+                ),
+            });
+        }
+    }
+    tiles
+}
+
+impl Tile {
+    /// Core clipped to an image of `width` x `height` pixels.
+    fn core_rect(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (x0, y0, x1, y1) = self.core;
+        let clip = |v: i32, max: u32| v.max(0).min(max as i32) as u32;
+
+        (
+            clip(x0, width),
+            clip(y0, height),
+            clip(x1, width),
+            clip(y1, height),
+        )
+    }
+
+    ///
+    /// Move polygons of the tile into image coordinates, keeping only ones
+    /// centered in the core. Polygons crossing the seam stay with one of the
+    /// tiles and are blended with polygons of the other one when rendered.
+    ///
+    pub fn stitch(&self, polygons: Vec<Polygon>) -> Vec<Polygon> {
+        let shift = Point {
+            x: self.x as i32,
+            y: self.y as i32,
+        };
+        let (x0, y0, x1, y1) = self.core;
+
+        polygons
+            .into_iter()
+            .map(|mut p| {
+                for point in &mut p.points {
+                    *point = *point + shift;
+                }
+                p
+            })
+            .filter(|p| {
+                let (x, y) = p.center();
+                x >= x0 && x < x1 && y >= y0 && y < y1
+            })
+            .collect()
+    }
+}
+
+///
+/// Reference image read lazily. PNG images are decoded row by row with
+/// `rows`, other formats are decoded at once.
+///
+pub enum Source {
+    Png {
+        path: PathBuf,
+        width: u32,
+        height: u32,
+    },
+    Decoded(image::RgbImage),
+}
+
+impl Source {
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        let path = path.as_ref();
+
+        if image::ImageFormat::from_path(path)? == image::ImageFormat::Png {
+            let (width, height) = Source::decoder(path)?.dimensions();
+            return Ok(Source::Png {
+                path: path.to_owned(),
+                width,
+                height,
+            });
+        }
+        Ok(Source::Decoded(image::open(path)?.to_rgb()))
+    }
+
+    fn decoder(path: &Path) -> ImageResult<PngDecoder<BufReader<File>>> {
+        PngDecoder::new(BufReader::new(File::open(path)?))
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Source::Png { width, height, .. } => (*width, *height),
+            Source::Decoded(img) => img.dimensions(),
+        }
+    }
+
+    /// Reader of rows of the image, from the top down.
+    pub fn rows(&self) -> ImageResult<Rows<'_>> {
+        let (width, _) = self.dimensions();
+        let decoder = match self {
+            Source::Png { path, .. } => {
+                let decoder = Source::decoder(path)?;
+                let color = decoder.color_type();
+                let bpp = color.bytes_per_pixel() as usize;
+                Decoder::Png {
+                    channels: color.channel_count() as usize,
+                    bpp,
+                    raw: vec![0; width as usize * bpp],
+                    reader: Box::new(decoder.into_reader()?),
+                }
+            }
+            Source::Decoded(img) => Decoder::Decoded(img),
+        };
+
+        Ok(Rows {
+            decoder,
+            width,
+            next: 0,
+            first: 0,
+            kept: VecDeque::new(),
+        })
+    }
+
+    ///
+    /// Most common color of the image with channels rounded to 5 bits, the
+    /// mean of pixels of that color. Read row by row.
+    ///
+    pub fn dominant(&self) -> ImageResult<Color> {
+        let mut bins = vec![(0u64, [0u64; 3]); 1 << 15];
+        let mut rows = self.rows()?;
+        let mut row = vec![0; 3 * self.dimensions().0 as usize];
+
+        for _ in 0..self.dimensions().1 {
+            rows.decode(&mut row)?;
+            for px in row.chunks_exact(3) {
+                let bin = c![(px[ch] >> 3) as usize, for ch in 0..3];
+                let bin = &mut bins[bin[0] << 10 | bin[1] << 5 | bin[2]];
+                bin.0 += 1;
+                for (sum, v) in bin.1.iter_mut().zip(px) {
+                    *sum += *v as u64;
+                }
+            }
+        }
+
+        let (n, sums) = bins.iter().max_by_key(|(n, _)| *n).unwrap();
+        let mean = |ch: usize| (sums[ch] / (*n).max(1)) as u8;
+        Ok(Color(image::Rgb([mean(0), mean(1), mean(2)])))
+    }
+}
+
+enum Decoder<'a> {
+    Png {
+        reader: Box<dyn Read>,
+        bpp: usize,
+        channels: usize,
+        raw: Vec<u8>,
+    },
+    Decoded(&'a image::RgbImage),
+}
+
+///
+/// Rows of a `Source` decoded once, from the top down. Rows of the last
+/// requested band are kept, so that bands may overlap as long as they don't
+/// start above the previous one.
+///
+pub struct Rows<'a> {
+    decoder: Decoder<'a>,
+    width: u32,
+    /// Next row to decode.
+    next: u32,
+    /// Row at the front of `kept`, rows `first..next` are kept.
+    first: u32,
+    kept: VecDeque<Vec<u8>>,
+}
+
+impl<'a> Rows<'a> {
+    /// Decode row `next` into `row` as raw RGB.
+    fn decode(&mut self, row: &mut [u8]) -> ImageResult<()> {
+        let y = self.next as usize;
+        self.next += 1;
+
+        let (reader, bpp, channels, raw) = match &mut self.decoder {
+            Decoder::Png {
+                reader,
+                bpp,
+                channels,
+                raw,
+            } => (reader, *bpp, *channels, raw),
+            Decoder::Decoded(img) => {
+                let len = row.len();
+                let img: &[u8] = img;
+                row.copy_from_slice(&img[y * len..(y + 1) * len]);
+                return Ok(());
+            }
+        };
+        // Samples are big endian, the first byte is the most significant.
+        let sample = bpp / channels;
+
+        reader.read_exact(raw)?;
+        for (px, out) in raw.chunks_exact(bpp).zip(row.chunks_exact_mut(3)) {
+            for (ch, out) in out.iter_mut().enumerate() {
+                // Gray images have one color channel, alpha is dropped.
+                let ch = if channels < 3 { 0 } else { ch };
+                *out = px[ch * sample];
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Call `f` with every row from `top` to `bottom` as raw RGB. Rows above
+    /// `top` are dropped, rows below the kept ones are decoded.
+    ///
+    fn band(
+        &mut self,
+        top: u32,
+        bottom: u32,
+        mut f: impl FnMut(u32, &[u8]),
+    ) -> ImageResult<()> {
+        if top < self.first {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("row {} was already dropped", top),
+            )
+            .into());
+        }
+        while self.first < top && !self.kept.is_empty() {
+            self.kept.pop_front();
+            self.first += 1;
+        }
+
+        let mut skipped = vec![0; 3 * self.width as usize];
+        while self.next < top {
+            self.decode(&mut skipped)?;
+        }
+        self.first = self.first.max(top);
+        while self.next < bottom {
+            let mut row = vec![0; 3 * self.width as usize];
+            self.decode(&mut row)?;
+            self.kept.push_back(row);
+        }
+
+        for y in top..bottom {
+            f(y, &self.kept[(y - self.first) as usize]);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Parts of the image under `tiles`, read in one pass. Tiles must not
+    /// start above ones cropped before.
+    ///
+    pub fn crop(
+        &mut self,
+        tiles: &[Tile],
+    ) -> ImageResult<Vec<image::RgbImage>> {
+        let mut crops =
+            c![image::RgbImage::new(t.width, t.height), for t in tiles];
+        let top = tiles.iter().map(|t| t.y).min().unwrap_or(self.first);
+        let bottom = tiles.iter().map(|t| t.y + t.height).max().unwrap_or(top);
+
+        self.band(top, bottom, |y, row| {
+            for (t, crop) in tiles.iter().zip(crops.iter_mut()) {
+                if y < t.y || y >= t.y + t.height {
+                    continue;
+                }
+                let (x0, x1) = (3 * t.x as usize, 3 * (t.x + t.width) as usize);
+                let start = (y - t.y) as usize * (x1 - x0);
+                let crop: &mut [u8] = crop;
+                crop[start..start + x1 - x0].copy_from_slice(&row[x0..x1]);
+            }
+        })?;
+        Ok(crops)
+    }
+}
+
+///
+/// Optimize tiles with `f` given their part of the image and stitch
+/// results. At most `resident` tiles are cropped and processed, and so kept
+/// in memory, at once. Tiles are taken in row-major order, so that every
+/// row of the image is decoded once.
+///
+pub fn run<F>(
+    source: &Source,
+    tiles: &[Tile],
+    resident: usize,
+    f: F,
+) -> ImageResult<Vec<Polygon>>
+where
+    F: Fn(&Tile, image::RgbImage) -> Vec<Polygon> + Sync,
+{
+    let mut polygons = Vec::new();
+    let mut rows = source.rows()?;
+
+    for batch in tiles.chunks(resident.max(1)) {
+        let done = batch
+            .iter()
+            .zip(rows.crop(batch)?)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(tile, img)| tile.stitch(f(tile, img)))
+            .collect::<Vec<_>>();
+        for (n, tile_polygons) in done.into_iter().enumerate() {
+            eprintln!(
+                "tile at {}x{}: {} polygons",
+                batch[n].x,
+                batch[n].y,
+                tile_polygons.len()
+            );
+            polygons.extend(tile_polygons);
+        }
+    }
+    Ok(polygons)
+}
+
+///
+/// Render polygons core by core of `tiles` and write them to `out` as PNG.
+/// Tiles go one row at a time, `resident` of them at once, each with only
+/// polygons over its core, so only rows of the current row of tiles are
+/// kept in memory.
+///
+pub fn render<W: Write>(
+    tiles: &[Tile],
+    resident: usize,
+    (width, height): (u32, u32),
+    bg: Color,
+    polygons: &[Polygon],
+    out: W,
+) -> io::Result<W> {
+    let mut png = png::Writer::new(out, width, height)?;
+    let mut rest = tiles;
+
+    while !rest.is_empty() {
+        let (_, top, _, bottom) = rest[0].core_rect(width, height);
+        let n = rest
+            .iter()
+            .take_while(|t| t.core_rect(width, height).1 == top)
+            .count();
+        let (row, next) = rest.split_at(n);
+        rest = next;
+
+        let over = |p: &Polygon, (x0, y0, x1, y1): (u32, u32, u32, u32)| {
+            let (left, up, right, down) = p.bbox();
+            left < x1 as i32
+                && right >= x0 as i32
+                && up < y1 as i32
+                && down >= y0 as i32
+        };
+        let band =
+            c![p, for p in polygons, if over(p, (0, top, width, bottom))];
+        let stride = 3 * width as usize;
+        let mut rows = vec![0; stride * (bottom - top) as usize];
+
+        for batch in row.chunks(resident.max(1)) {
+            let parts = batch
+                .par_iter()
+                .map(|tile| {
+                    let rect = tile.core_rect(width, height);
+                    let (x0, y0, x1, y1) = rect;
+                    let shift = Point {
+                        x: -(x0 as i32),
+                        y: -(y0 as i32),
+                    };
+                    let mut shifted =
+                        c![(*p).clone(), for p in &band, if over(p, rect)];
+                    for point in shifted.iter_mut().flat_map(|p| &mut p.points)
+                    {
+                        *point = *point + shift;
+                    }
+
+                    let size = (x1 - x0, y1 - y0);
+                    let grid =
+                        Grid::new(size.0 as i32, size.1 as i32, &shifted);
+                    render::draw_rect((0, 0), size, bg, &shifted, &grid)
+                })
+                .collect::<Vec<_>>();
+
+            for (tile, part) in batch.iter().zip(parts) {
+                let (x0, _, _, _) = tile.core_rect(width, height);
+                let len = 3 * part.width() as usize;
+                for (y, part) in part.chunks(len.max(1)).enumerate() {
+                    let start = y * stride + 3 * x0 as usize;
+                    rows[start..start + len].copy_from_slice(part);
+                }
+            }
+        }
+
+        for row in rows.chunks(stride.max(1)) {
+            png.write_row(row)?;
+        }
+    }
+    png.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::random_triangle;
+
+    fn reference() -> image::RgbImage {
+        image::RgbImage::from_fn(45, 31, |x, y| {
+            image::Rgb([(x * 5) as u8, (y * 8) as u8, ((x * y) % 256) as u8])
+        })
+    }
+
+    fn saved(name: &str, img: &image::DynamicImage) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "pido-tile-{}-{}.png",
+            std::process::id(),
+            name
+        ));
+        img.save(&path).unwrap();
+        path
+    }
+
+    fn crops_match(img: image::DynamicImage, name: &str) {
+        let path = saved(name, &img);
+        let source = Source::open(&path).unwrap();
+        let rgb = img.to_rgb();
+        assert!(matches!(source, Source::Png { .. }));
+        assert_eq!(source.dimensions(), (45, 31));
+
+        let tiles = tiles(45, 31, 16, 4);
+        let lazy = source.rows().unwrap().crop(&tiles).unwrap();
+        for (tile, crop) in tiles.iter().zip(&lazy) {
+            let expected =
+                image::RgbImage::from_fn(tile.width, tile.height, |x, y| {
+                    *rgb.get_pixel(tile.x + x, tile.y + y)
+                });
+            assert_eq!(**crop, *expected);
+        }
+
+        let mut rows = source.rows().unwrap();
+        let batched = tiles
+            .chunks(3)
+            .flat_map(|batch| rows.crop(batch).unwrap())
+            .collect::<Vec<_>>();
+        assert!(rows.crop(&tiles[..1]).is_err());
+
+        let decoded = Source::Decoded(rgb);
+        let whole = decoded.rows().unwrap().crop(&tiles).unwrap();
+        assert_eq!(
+            c![crop.into_raw(), for crop in whole],
+            c![crop.clone().into_raw(), for crop in &lazy]
+        );
+        assert_eq!(
+            c![crop.into_raw(), for crop in batched],
+            c![crop.into_raw(), for crop in lazy]
+        );
+        assert_eq!(source.dominant().unwrap(), decoded.dominant().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lazy_png_crops() {
+        let img = reference();
+        crops_match(image::DynamicImage::ImageRgb8(img.clone()), "rgb");
+        crops_match(
+            image::DynamicImage::ImageRgba8(
+                image::DynamicImage::ImageRgb8(img).to_rgba(),
+            ),
+            "rgba",
+        );
+        crops_match(
+            image::DynamicImage::ImageRgb8(reference()).grayscale(),
+            "gray",
+        );
+    }
+
+    #[test]
+    fn tiled_render_matches_whole() {
+        let (width, height) = (45, 31);
+        let bg = Color(image::Rgb([9, 8, 7]));
+        let polygons = c![random_triangle(width as i32, height as i32, 5), for _i in 0..100];
+
+        let png = render(
+            &tiles(width, height, 16, 4),
+            2,
+            (width, height),
+            bg,
+            &polygons,
+            Vec::new(),
+        )
+        .unwrap();
+        let tiled = image::load_from_memory(&png).unwrap().to_rgb();
+        let whole = render::draw(width, height, bg, &polygons);
+        assert_eq!(*tiled, *whole);
+    }
+}